@@ -0,0 +1,188 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{read, DirBuilder},
+    io,
+    os::unix::fs::DirBuilderExt,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    ChaCha20Poly1305, Key, Nonce,
+};
+use hkdf::Hkdf;
+use log::debug;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::{file::write_private, secret::Secret};
+
+/// The version of the cache file format, written as its first byte.
+const VERSION: u8 = 1;
+const NONCE_SIZE: usize = 12;
+
+#[derive(Debug)]
+pub enum Error {
+    DecryptError(PathBuf),
+    EncryptError,
+    IoError(io::Error),
+    JsonError(serde_json::Error),
+    NoKeyError,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::DecryptError(path) => write!(
+                f,
+                "could not decrypt {:?}, it may have been encrypted with a different key",
+                path
+            ),
+            Error::EncryptError => write!(f, "could not encrypt the environment"),
+            Error::IoError(e) => e.fmt(f),
+            Error::JsonError(e) => e.fmt(f),
+            Error::NoKeyError => write!(
+                f,
+                "--cache-dir requires --cache-key-file or a supplied vault token"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::DecryptError(_) | Error::EncryptError | Error::NoKeyError => None,
+            Error::IoError(e) => Some(e),
+            Error::JsonError(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Error {
+        Error::JsonError(e)
+    }
+}
+
+/// A cached environment, along with when it was saved.
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    saved: u64,
+    pub env: HashMap<String, String>,
+}
+
+impl Entry {
+    pub fn age(&self) -> Duration {
+        Duration::from_secs(now().saturating_sub(self.saved))
+    }
+}
+
+/// The last environment fetched for a service, encrypted at rest, to fall
+/// back to when Consul or Vault can't be reached.
+pub struct Cache {
+    path: PathBuf,
+    service: String,
+    max_age: Duration,
+    cipher: ChaCha20Poly1305,
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Cache")
+            .field("path", &self.path)
+            .field("max_age", &self.max_age)
+            .finish()
+    }
+}
+
+impl Cache {
+    /// The cache for `service` in `dir`, encrypted with a key derived from
+    /// `secret`. Cached environments older than `max_age` aren't used.
+    pub fn new(dir: &Path, service: &str, secret: &Secret, max_age: Duration) -> Cache {
+        let hkdf = Hkdf::<Sha256>::new(Some(b"avvoenv cache"), secret.0.as_bytes());
+        let mut key = Key::default();
+        hkdf.expand(service.as_bytes(), &mut key)
+            .expect("invalid key length");
+        Cache {
+            path: dir.join(format!("{}.cache", service)),
+            service: service.to_owned(),
+            max_age,
+            cipher: ChaCha20Poly1305::new(&key),
+        }
+    }
+
+    /// Saves `env`, replacing any previously cached environment.
+    pub fn save(&self, env: &HashMap<String, String>) -> Result<(), Error> {
+        debug!("Caching environment in {:?}", self.path);
+        let entry = Entry {
+            saved: now(),
+            env: env.clone(),
+        };
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: &serde_json::to_vec(&entry)?,
+                    aad: self.service.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::EncryptError)?;
+        let mut contents = Vec::with_capacity(1 + nonce.len() + ciphertext.len());
+        contents.push(VERSION);
+        contents.extend_from_slice(&nonce);
+        contents.extend_from_slice(&ciphertext);
+        if let Some(dir) = self.path.parent() {
+            DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        }
+        write_private(&self.path, &contents)?;
+        Ok(())
+    }
+
+    /// Loads the cached environment, or `None` if nothing has been cached, or
+    /// it's too old.
+    pub fn load(&self) -> Result<Option<Entry>, Error> {
+        debug!("Reading cached environment from {:?}", self.path);
+        let contents = match read(&self.path) {
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            result => result?,
+        };
+        if contents.len() < 1 + NONCE_SIZE || contents[0] != VERSION {
+            return Err(Error::DecryptError(self.path.clone()));
+        }
+        let (nonce, ciphertext) = contents[1..].split_at(NONCE_SIZE);
+        let plaintext = self
+            .cipher
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: self.service.as_bytes(),
+                },
+            )
+            .map_err(|_| Error::DecryptError(self.path.clone()))?;
+        let entry: Entry = serde_json::from_slice(&plaintext)?;
+        if entry.age() > self.max_age {
+            debug!("Cached environment is {:?} old, ignoring", entry.age());
+            return Ok(None);
+        }
+        Ok(Some(entry))
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
@@ -1,17 +1,25 @@
-use std::{error::Error, fmt};
+use std::{error::Error, fmt, io, path::PathBuf};
 
 #[derive(Debug)]
 pub enum ClientError {
+    Base64Error {
+        url: reqwest::Url,
+        source: base64::DecodeError,
+    },
     BaseUrlError(reqwest::Url),
+    IoError(PathBuf, io::Error),
     JsonError {
         url: reqwest::Url,
         source: serde_json::Error,
     },
+    NotFound(reqwest::Url),
     RequestError {
         url: reqwest::Url,
         source: reqwest::Error,
     },
+    ResolveError(reqwest::Url, io::Error),
     ServerError(reqwest::blocking::Response),
+    TlsError(PathBuf, reqwest::Error),
     UrlError(url::ParseError),
 }
 
@@ -22,16 +30,37 @@ impl ClientError {
     {
         (url, detail).into()
     }
+
+    /// Whether the server could not be reached, or reported that it is
+    /// temporarily unable to handle the request.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            ClientError::RequestError { source, .. } => {
+                source.is_connect() || source.is_timeout() || source.is_request()
+            }
+            ClientError::ResolveError(..) => true,
+            ClientError::ServerError(response) => {
+                response.status().is_server_error()
+                    || response.status() == reqwest::StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for ClientError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            ClientError::Base64Error { url, source } => write!(f, "{}: {}", url, source),
             ClientError::BaseUrlError(url) => write!(f, "Cannot be a Base: {:?}", url),
+            ClientError::IoError(path, e) => write!(f, "{:?}: {}", path, e),
             ClientError::JsonError { url, source } => write!(f, "{}: {}", url, source),
+            ClientError::NotFound(url) => write!(f, "{}: Not Found", url),
             ClientError::RequestError { ref source, .. } if source.url().is_some() => source.fmt(f),
             ClientError::RequestError { url, source } => write!(f, "{}: {}", url, source),
+            ClientError::ResolveError(url, e) => write!(f, "{}: {}", url, e),
             ClientError::ServerError(response) => write!(f, "{:?}", response),
+            ClientError::TlsError(path, e) => write!(f, "{:?}: {}", path, e),
             ClientError::UrlError(e) => e.fmt(f),
         }
     }
@@ -40,9 +69,15 @@ impl fmt::Display for ClientError {
 impl Error for ClientError {
     fn source(&self) -> Option<&(dyn Error + 'static)> {
         match self {
-            ClientError::BaseUrlError(_) | ClientError::ServerError(_) => None,
+            ClientError::BaseUrlError(_)
+            | ClientError::NotFound(_)
+            | ClientError::ServerError(_) => None,
+            ClientError::Base64Error { source, .. } => Some(source),
+            ClientError::IoError(_, e) | ClientError::ResolveError(_, e) => Some(e),
             ClientError::JsonError { source, .. } => Some(source),
-            ClientError::RequestError { source, .. } => Some(source),
+            ClientError::RequestError { source, .. } | ClientError::TlsError(_, source) => {
+                Some(source)
+            }
             ClientError::UrlError(e) => Some(e),
         }
     }
@@ -60,6 +95,12 @@ impl From<(reqwest::Url, reqwest::Error)> for ClientError {
     }
 }
 
+impl From<(reqwest::Url, base64::DecodeError)> for ClientError {
+    fn from((url, source): (reqwest::Url, base64::DecodeError)) -> ClientError {
+        ClientError::Base64Error { url, source }
+    }
+}
+
 impl From<(reqwest::Url, serde_json::Error)> for ClientError {
     fn from((url, source): (reqwest::Url, serde_json::Error)) -> ClientError {
         ClientError::JsonError { url, source }
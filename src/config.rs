@@ -0,0 +1,859 @@
+use std::{
+    collections::BTreeMap,
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use dirs::home_dir;
+use glob::Pattern;
+use reqwest::Url;
+use serde::{de, Deserialize, Deserializer};
+use structopt::clap::ArgMatches;
+
+use crate::{secret::Secret, FetchOpts};
+
+/// The name of the project config file, looked for in the current directory
+/// and each of its parents.
+const PROJECT_FILE: &str = ".avvoenv.toml";
+
+#[derive(Debug)]
+pub enum Error {
+    ConnectionError {
+        path: PathBuf,
+        profile: Option<String>,
+        keys: Vec<&'static str>,
+    },
+    IoError(PathBuf, io::Error),
+    NoProfileError(String),
+    TomlError {
+        path: PathBuf,
+        profile: Option<String>,
+        error: toml::de::Error,
+    },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::ConnectionError {
+                path,
+                profile,
+                keys,
+            } => {
+                write!(f, "{:?} can't set {}", path, keys.join(", "))?;
+                if let Some(name) = profile {
+                    write!(f, " in profile {:?}", name)?;
+                }
+                write!(f, ", only the user config file can")
+            }
+            Error::IoError(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            Error::NoProfileError(name) => write!(f, "no profile {:?} in any config file", name),
+            Error::TomlError {
+                path,
+                profile: Some(name),
+                error,
+            } => write!(f, "invalid profile {:?} in {:?}: {}", name, path, error),
+            Error::TomlError {
+                path,
+                profile: None,
+                error,
+            } => write!(f, "invalid config in {:?}: {}", path, error),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(_, e) => Some(e),
+            Error::ConnectionError { .. } | Error::NoProfileError(_) => None,
+            Error::TomlError { error, .. } => Some(error),
+        }
+    }
+}
+
+/// Options set by a config file, either at the top level or in a
+/// `[profile.<name>]` table. Keys are the long names of the `FetchOpts`
+/// options, and should be kept in sync with them.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+struct Profile {
+    service: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    consul: Option<Url>,
+    #[serde(default, deserialize_with = "parse")]
+    consul_token: Option<Secret>,
+    consul_token_file: Option<PathBuf>,
+    #[serde(default)]
+    consul_datacenter: Vec<String>,
+    consul_namespace: Option<String>,
+    consul_ca_cert: Option<PathBuf>,
+    consul_ca_path: Option<PathBuf>,
+    consul_client_cert: Option<PathBuf>,
+    consul_client_key: Option<PathBuf>,
+    consul_skip_verify: Option<bool>,
+    consul_tls_server_name: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    vault: Option<Url>,
+    vault_namespace: Option<String>,
+    #[serde(default)]
+    vault_path_namespace: BTreeMap<String, String>,
+    vault_ca_cert: Option<PathBuf>,
+    vault_ca_path: Option<PathBuf>,
+    vault_client_cert: Option<PathBuf>,
+    vault_client_key: Option<PathBuf>,
+    vault_skip_verify: Option<bool>,
+    vault_tls_server_name: Option<String>,
+    dev: Option<bool>,
+    #[serde(default)]
+    add: BTreeMap<String, String>,
+    #[serde(default, deserialize_with = "parse_all")]
+    include: Vec<Pattern>,
+    #[serde(default, deserialize_with = "parse_all")]
+    exclude: Vec<Pattern>,
+    #[serde(default, deserialize_with = "parse")]
+    vault_token: Option<Secret>,
+    #[serde(default, deserialize_with = "parse")]
+    vault_wrapped_token: Option<Secret>,
+    #[serde(default, deserialize_with = "parse")]
+    vault_wrapped_path: Option<Pattern>,
+    #[serde(default, deserialize_with = "parse")]
+    app_user: Option<Secret>,
+    app_id: Option<String>,
+    secrets_version: Option<u64>,
+    config_version: Option<u64>,
+    role_id: Option<String>,
+    #[serde(default, deserialize_with = "parse")]
+    secret_id: Option<Secret>,
+    secret_id_file: Option<PathBuf>,
+    kubernetes_role: Option<String>,
+    kubernetes_mount: Option<String>,
+    kubernetes_token_file: Option<PathBuf>,
+    pki_role: Option<String>,
+    pki_mount: Option<String>,
+    pki_common_name: Option<String>,
+    pki_cert_file: Option<PathBuf>,
+    pki_key_file: Option<PathBuf>,
+    pki_ca_file: Option<PathBuf>,
+    transit_key: Option<String>,
+    transit_mount: Option<String>,
+    interpolate: Option<bool>,
+    interpolate_strict: Option<bool>,
+    schema: Option<PathBuf>,
+    no_schema: Option<bool>,
+    no_rancher_metadata: Option<bool>,
+    http_connect_timeout: Option<u64>,
+    http_timeout: Option<u64>,
+    http_max_attempts: Option<u32>,
+    http_max_backoff: Option<u64>,
+    cache_dir: Option<PathBuf>,
+    cache_key_file: Option<PathBuf>,
+    cache_max_age: Option<u64>,
+}
+
+impl Profile {
+    /// Makes relative paths relative to `dir`, the directory of the config
+    /// file, rather than wherever avvoenv happens to be run from.
+    fn resolve(&mut self, dir: &Path) {
+        let mut paths = [
+            &mut self.consul_token_file,
+            &mut self.consul_ca_cert,
+            &mut self.consul_ca_path,
+            &mut self.consul_client_cert,
+            &mut self.consul_client_key,
+            &mut self.vault_ca_cert,
+            &mut self.vault_ca_path,
+            &mut self.vault_client_cert,
+            &mut self.vault_client_key,
+            &mut self.secret_id_file,
+            &mut self.kubernetes_token_file,
+            &mut self.pki_cert_file,
+            &mut self.pki_key_file,
+            &mut self.pki_ca_file,
+            &mut self.cache_dir,
+            &mut self.cache_key_file,
+            &mut self.schema,
+        ];
+        for path in paths.iter_mut().filter_map(|path| path.as_mut()) {
+            *path = dir.join(&path);
+        }
+    }
+
+    /// The connection settings the profile sets: where Consul and Vault are,
+    /// how to verify them, and how to authenticate with them.
+    fn connection_keys(&self) -> Vec<&'static str> {
+        let keys = [
+            ("consul", self.consul.is_some()),
+            ("consul-token", self.consul_token.is_some()),
+            ("consul-token-file", self.consul_token_file.is_some()),
+            ("consul-ca-cert", self.consul_ca_cert.is_some()),
+            ("consul-ca-path", self.consul_ca_path.is_some()),
+            ("consul-client-cert", self.consul_client_cert.is_some()),
+            ("consul-client-key", self.consul_client_key.is_some()),
+            ("consul-skip-verify", self.consul_skip_verify.is_some()),
+            (
+                "consul-tls-server-name",
+                self.consul_tls_server_name.is_some(),
+            ),
+            ("vault", self.vault.is_some()),
+            ("vault-ca-cert", self.vault_ca_cert.is_some()),
+            ("vault-ca-path", self.vault_ca_path.is_some()),
+            ("vault-client-cert", self.vault_client_cert.is_some()),
+            ("vault-client-key", self.vault_client_key.is_some()),
+            ("vault-skip-verify", self.vault_skip_verify.is_some()),
+            (
+                "vault-tls-server-name",
+                self.vault_tls_server_name.is_some(),
+            ),
+            ("dev", self.dev.is_some()),
+            ("vault-token", self.vault_token.is_some()),
+            ("vault-wrapped-token", self.vault_wrapped_token.is_some()),
+            ("vault-wrapped-path", self.vault_wrapped_path.is_some()),
+            ("app-user", self.app_user.is_some()),
+            ("app-id", self.app_id.is_some()),
+            ("role-id", self.role_id.is_some()),
+            ("secret-id", self.secret_id.is_some()),
+            ("secret-id-file", self.secret_id_file.is_some()),
+            ("kubernetes-role", self.kubernetes_role.is_some()),
+            ("kubernetes-mount", self.kubernetes_mount.is_some()),
+            (
+                "kubernetes-token-file",
+                self.kubernetes_token_file.is_some(),
+            ),
+        ];
+        keys.iter()
+            .filter(|(_, set)| *set)
+            .map(|(key, _)| *key)
+            .collect()
+    }
+
+    /// Whether the profile sets a way of authenticating with Vault.
+    fn sets_auth(&self) -> bool {
+        self.dev.is_some()
+            || self.vault_token.is_some()
+            || self.vault_wrapped_token.is_some()
+            || self.app_user.is_some()
+            || self.app_id.is_some()
+            || self.role_id.is_some()
+            || self.kubernetes_role.is_some()
+    }
+
+    /// Sets the options in `opts` that the profile sets, other than those
+    /// `given` on the command line or with environment variables.
+    fn fill(&self, opts: &mut FetchOpts, given: &Given) {
+        fill_some(&mut opts.service, &self.service, given.service());
+        fill_some(
+            &mut opts.consul,
+            &self.consul,
+            given.any(&[("consul", Some("CONSUL_HTTP_ADDR"))]),
+        );
+        // a token and a token file are two ways of setting the same thing, so
+        // either one given takes the place of both
+        if !given.any(&[
+            ("consul-token", Some("CONSUL_HTTP_TOKEN")),
+            ("consul-token-file", Some("CONSUL_HTTP_TOKEN_FILE")),
+        ]) && (self.consul_token.is_some() || self.consul_token_file.is_some())
+        {
+            opts.consul_token = self.consul_token.clone();
+            opts.consul_token_file = self.consul_token_file.clone();
+        }
+        fill_all(
+            &mut opts.consul_datacenters,
+            &self.consul_datacenter,
+            given.any(&[("consul-datacenters", Some("CONSUL_DATACENTER"))]),
+        );
+        fill_some(
+            &mut opts.consul_namespace,
+            &self.consul_namespace,
+            given.any(&[("consul-namespace", Some("CONSUL_NAMESPACE"))]),
+        );
+        fill_some(
+            &mut opts.consul_ca_cert,
+            &self.consul_ca_cert,
+            given.any(&[("consul-ca-cert", Some("CONSUL_CACERT"))]),
+        );
+        fill_some(
+            &mut opts.consul_ca_path,
+            &self.consul_ca_path,
+            given.any(&[("consul-ca-path", Some("CONSUL_CAPATH"))]),
+        );
+        fill_some(
+            &mut opts.consul_client_cert,
+            &self.consul_client_cert,
+            given.any(&[("consul-client-cert", Some("CONSUL_CLIENT_CERT"))]),
+        );
+        fill_some(
+            &mut opts.consul_client_key,
+            &self.consul_client_key,
+            given.any(&[("consul-client-key", Some("CONSUL_CLIENT_KEY"))]),
+        );
+        fill(
+            &mut opts.consul_skip_verify,
+            &self.consul_skip_verify,
+            given.any(&[("consul-skip-verify", Some("CONSUL_HTTP_SSL_VERIFY"))]),
+        );
+        fill_some(
+            &mut opts.consul_tls_server_name,
+            &self.consul_tls_server_name,
+            given.any(&[("consul-tls-server-name", Some("CONSUL_TLS_SERVER_NAME"))]),
+        );
+
+        fill_some(
+            &mut opts.vault,
+            &self.vault,
+            given.any(&[("vault", Some("VAULT_ADDR"))]),
+        );
+        fill_some(
+            &mut opts.vault_namespace,
+            &self.vault_namespace,
+            given.any(&[("vault-namespace", Some("VAULT_NAMESPACE"))]),
+        );
+        fill_some(
+            &mut opts.vault_ca_cert,
+            &self.vault_ca_cert,
+            given.any(&[("vault-ca-cert", Some("VAULT_CACERT"))]),
+        );
+        fill_some(
+            &mut opts.vault_ca_path,
+            &self.vault_ca_path,
+            given.any(&[("vault-ca-path", Some("VAULT_CAPATH"))]),
+        );
+        fill_some(
+            &mut opts.vault_client_cert,
+            &self.vault_client_cert,
+            given.any(&[("vault-client-cert", Some("VAULT_CLIENT_CERT"))]),
+        );
+        fill_some(
+            &mut opts.vault_client_key,
+            &self.vault_client_key,
+            given.any(&[("vault-client-key", Some("VAULT_CLIENT_KEY"))]),
+        );
+        fill(
+            &mut opts.vault_skip_verify,
+            &self.vault_skip_verify,
+            given.any(&[("vault-skip-verify", Some("VAULT_SKIP_VERIFY"))]),
+        );
+        fill_some(
+            &mut opts.vault_tls_server_name,
+            &self.vault_tls_server_name,
+            given.any(&[("vault-tls-server-name", Some("VAULT_TLS_SERVER_NAME"))]),
+        );
+
+        // only one way of authenticating with vault is used, so one given
+        // takes the place of the profile's, and the profile's takes the place
+        // of any from a config file with lower precedence
+        if !given.any(AUTH_OPTIONS) && self.sets_auth() {
+            opts.dev = self.dev.unwrap_or(false);
+            opts.token = self.vault_token.clone();
+            opts.wrapped_token = self.vault_wrapped_token.clone();
+            opts.app_user = self.app_user.clone();
+            opts.app_id = self.app_id.clone();
+            opts.role_id = self.role_id.clone();
+            opts.kubernetes_role = self.kubernetes_role.clone();
+        }
+        fill(
+            &mut opts.wrapped_path,
+            &self.vault_wrapped_path,
+            given.any(&[("wrapped-path", Some("VAULT_WRAPPED_PATH"))]),
+        );
+        if !given.any(&[
+            ("secret-id", Some("VAULT_SECRET_ID")),
+            ("secret-id-file", Some("VAULT_SECRET_ID_FILE")),
+        ]) && (self.secret_id.is_some() || self.secret_id_file.is_some())
+        {
+            opts.secret_id = self.secret_id.clone();
+            opts.secret_id_file = self.secret_id_file.clone();
+        }
+        fill(
+            &mut opts.kubernetes_mount,
+            &self.kubernetes_mount,
+            given.any(&[("kubernetes-mount", Some("VAULT_KUBERNETES_MOUNT"))]),
+        );
+        fill(
+            &mut opts.kubernetes_token_file,
+            &self.kubernetes_token_file,
+            given.any(&[("kubernetes-token-file", Some("VAULT_KUBERNETES_TOKEN_FILE"))]),
+        );
+
+        fill_all(
+            &mut opts.include,
+            &self.include,
+            given.any(&[("include", None)]),
+        );
+        fill_all(
+            &mut opts.exclude,
+            &self.exclude,
+            given.any(&[("exclude", None)]),
+        );
+        fill_some(
+            &mut opts.secrets_version,
+            &self.secrets_version,
+            given.any(&[("secrets-version", Some("VAULT_SECRETS_VERSION"))]),
+        );
+        fill_some(
+            &mut opts.config_version,
+            &self.config_version,
+            given.any(&[("config-version", Some("CONSUL_CONFIG_VERSION"))]),
+        );
+
+        fill_some(
+            &mut opts.pki_role,
+            &self.pki_role,
+            given.any(&[("pki-role", Some("VAULT_PKI_ROLE"))]),
+        );
+        fill(
+            &mut opts.pki_mount,
+            &self.pki_mount,
+            given.any(&[("pki-mount", Some("VAULT_PKI_MOUNT"))]),
+        );
+        fill_some(
+            &mut opts.pki_common_name,
+            &self.pki_common_name,
+            given.any(&[("pki-common-name", Some("VAULT_PKI_COMMON_NAME"))]),
+        );
+        fill(
+            &mut opts.pki_cert_file,
+            &self.pki_cert_file,
+            given.any(&[("pki-cert-file", None)]),
+        );
+        fill(
+            &mut opts.pki_key_file,
+            &self.pki_key_file,
+            given.any(&[("pki-key-file", None)]),
+        );
+        fill(
+            &mut opts.pki_ca_file,
+            &self.pki_ca_file,
+            given.any(&[("pki-ca-file", None)]),
+        );
+        fill_some(
+            &mut opts.transit_key,
+            &self.transit_key,
+            given.any(&[("transit-key", Some("VAULT_TRANSIT_KEY"))]),
+        );
+        fill(
+            &mut opts.transit_mount,
+            &self.transit_mount,
+            given.any(&[("transit-mount", Some("VAULT_TRANSIT_MOUNT"))]),
+        );
+
+        fill(
+            &mut opts.interpolate,
+            &self.interpolate,
+            given.any(&[("interpolate", Some("AVVOENV_INTERPOLATE"))]),
+        );
+        fill(
+            &mut opts.interpolate_strict,
+            &self.interpolate_strict,
+            given.any(&[("interpolate-strict", Some("AVVOENV_INTERPOLATE_STRICT"))]),
+        );
+        if !given.any(&[("schema", Some("AVVOENV_SCHEMA")), ("no-schema", None)])
+            && (self.schema.is_some() || self.no_schema.is_some())
+        {
+            opts.schema = self.schema.clone();
+            opts.no_schema = self.no_schema.unwrap_or(false);
+        }
+        fill(
+            &mut opts.skip_rancher_metadata,
+            &self.no_rancher_metadata,
+            given.any(&[("skip-rancher-metadata", Some("NO_RANCHER_METADATA"))]),
+        );
+
+        fill(
+            &mut opts.http_connect_timeout,
+            &self.http_connect_timeout,
+            given.any(&[("http-connect-timeout", Some("AVVOENV_HTTP_CONNECT_TIMEOUT"))]),
+        );
+        fill(
+            &mut opts.http_timeout,
+            &self.http_timeout,
+            given.any(&[("http-timeout", Some("AVVOENV_HTTP_TIMEOUT"))]),
+        );
+        fill(
+            &mut opts.http_max_attempts,
+            &self.http_max_attempts,
+            given.any(&[("http-max-attempts", Some("AVVOENV_HTTP_MAX_ATTEMPTS"))]),
+        );
+        fill(
+            &mut opts.http_max_backoff,
+            &self.http_max_backoff,
+            given.any(&[("http-max-backoff", Some("AVVOENV_HTTP_MAX_BACKOFF"))]),
+        );
+        fill_some(
+            &mut opts.cache_dir,
+            &self.cache_dir,
+            given.any(&[("cache-dir", Some("AVVOENV_CACHE_DIR"))]),
+        );
+        fill_some(
+            &mut opts.cache_key_file,
+            &self.cache_key_file,
+            given.any(&[("cache-key-file", Some("AVVOENV_CACHE_KEY_FILE"))]),
+        );
+        fill(
+            &mut opts.cache_max_age,
+            &self.cache_max_age,
+            given.any(&[("cache-max-age", Some("AVVOENV_CACHE_MAX_AGE"))]),
+        );
+    }
+}
+
+/// The options for authenticating with Vault, and their environment
+/// variables.
+const AUTH_OPTIONS: &[(&str, Option<&str>)] = &[
+    ("dev", None),
+    ("token", Some("VAULT_TOKEN")),
+    ("wrapped-token", Some("VAULT_WRAPPED_TOKEN")),
+    ("app-user", Some("VAULT_APP_USER")),
+    ("app-id", Some("VAULT_APP_ID")),
+    ("role-id", Some("VAULT_ROLE_ID")),
+    ("kubernetes-role", Some("VAULT_KUBERNETES_ROLE")),
+];
+
+/// Deserializes an option from a string the same way as its command line
+/// argument.
+fn parse<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let s = String::deserialize(deserializer)?;
+    s.parse().map(Some).map_err(de::Error::custom)
+}
+
+/// Deserializes an option that can be given more than once from an array of
+/// strings the same way as its command line arguments.
+fn parse_all<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: fmt::Display,
+{
+    let strings = Vec::<String>::deserialize(deserializer)?;
+    strings
+        .iter()
+        .map(|s| s.parse().map_err(de::Error::custom))
+        .collect()
+}
+
+/// Sets `option`, which has a default, to `value` if there is one, unless the
+/// option was `given`.
+fn fill<T: Clone>(option: &mut T, value: &Option<T>, given: bool) {
+    if let (Some(value), false) = (value, given) {
+        *option = value.clone();
+    }
+}
+
+/// Sets `option`, which is unset by default, to `value` if there is one,
+/// unless the option was `given`.
+fn fill_some<T: Clone>(option: &mut Option<T>, value: &Option<T>, given: bool) {
+    if let (Some(value), false) = (value, given) {
+        *option = Some(value.clone());
+    }
+}
+
+/// Sets `option` to `values` if there are any, unless the option was `given`.
+fn fill_all<T: Clone>(option: &mut Vec<T>, values: &[T], given: bool) {
+    if !values.is_empty() && !given {
+        *option = values.to_vec();
+    }
+}
+
+/// Adds the `pairs` with keys not already set to the front of `option`, so
+/// that those given take precedence.
+fn fill_pairs(option: &mut Vec<(String, String)>, pairs: BTreeMap<String, String>) {
+    let pairs: Vec<_> = pairs
+        .into_iter()
+        .filter(|(key, _)| !option.iter().any(|(k, _)| k == key))
+        .collect();
+    option.splice(0..0, pairs);
+}
+
+/// Which options were given on the command line, or with environment
+/// variables, and so aren't set by the config files.
+pub struct Given<'a, 'b>(&'a ArgMatches<'b>);
+
+impl<'a, 'b> Given<'a, 'b> {
+    pub fn new(matches: &'a ArgMatches<'b>) -> Self {
+        Given(matches)
+    }
+
+    /// Whether any of `options`, as pairs of argument name and environment
+    /// variable, were given.
+    fn any(&self, options: &[(&str, Option<&str>)]) -> bool {
+        options.iter().any(|(arg, env)| {
+            self.0.occurrences_of(arg) > 0 || env.is_some_and(|env| std::env::var_os(env).is_some())
+        })
+    }
+
+    fn service(&self) -> bool {
+        self.any(&[("service", Some("SERVICE"))])
+    }
+}
+
+/// A config file, with the options set at the top level, and its profiles.
+#[derive(Debug)]
+struct File {
+    path: PathBuf,
+    defaults: Profile,
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl File {
+    fn load(path: PathBuf) -> Result<File, Error> {
+        let contents = fs::read_to_string(&path).map_err(|e| Error::IoError(path.clone(), e))?;
+        let toml_error = |profile: Option<&String>, error| Error::TomlError {
+            path: path.clone(),
+            profile: profile.cloned(),
+            error,
+        };
+        let mut table: toml::value::Table =
+            toml::from_str(&contents).map_err(|e| toml_error(None, e))?;
+        let profiles: BTreeMap<String, toml::Value> = table
+            .remove("profile")
+            .map(toml::Value::try_into)
+            .transpose()
+            .map_err(|e| toml_error(None, e))?
+            .unwrap_or_default();
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let mut defaults: Profile = toml::Value::Table(table)
+            .try_into()
+            .map_err(|e| toml_error(None, e))?;
+        defaults.resolve(dir);
+        let mut resolved = BTreeMap::new();
+        for (name, value) in profiles {
+            let mut profile: Profile = value.try_into().map_err(|e| toml_error(Some(&name), e))?;
+            profile.resolve(dir);
+            resolved.insert(name, profile);
+        }
+        Ok(File {
+            path,
+            defaults,
+            profiles: resolved,
+        })
+    }
+
+    /// Checks the file doesn't set any connection settings, as a project file
+    /// comes with the project, so could otherwise send the user's tokens to a
+    /// server of its choosing.
+    fn check_project(&self) -> Result<(), Error> {
+        let profiles = self
+            .profiles
+            .iter()
+            .map(|(name, profile)| (Some(name), profile));
+        for (name, profile) in std::iter::once((None, &self.defaults)).chain(profiles) {
+            let keys = profile.connection_keys();
+            if !keys.is_empty() {
+                return Err(Error::ConnectionError {
+                    path: self.path.clone(),
+                    profile: name.cloned(),
+                    keys,
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The user config file, `$XDG_CONFIG_HOME/avvoenv/config.toml`, defaulting
+/// to `~/.config/avvoenv/config.toml`.
+fn user_file() -> Option<PathBuf> {
+    let dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => home_dir()?.join(".config"),
+    };
+    Some(dir.join("avvoenv").join("config.toml"))
+}
+
+/// The nearest project config file, in the current directory or one of its
+/// parents.
+fn project_file() -> Option<PathBuf> {
+    let cwd = std::env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// The options set by the user and project config files, and the profile
+/// selected from them.
+#[derive(Debug)]
+pub struct Config {
+    /// The paths of the config files read.
+    pub paths: Vec<PathBuf>,
+    /// The options set by each file and profile, lowest precedence first.
+    profiles: Vec<Profile>,
+}
+
+impl Config {
+    /// Reads the user and project config files, selecting `profile` from
+    /// them if given. Options from the user file come before the project
+    /// file, and top level options before the profile's. Only the user file
+    /// can set connection settings.
+    pub fn load(profile: Option<&str>) -> Result<Config, Error> {
+        let mut files = Vec::new();
+        if let Some(path) = user_file().filter(|path| path.is_file()) {
+            files.push(File::load(path)?);
+        }
+        if let Some(path) = project_file() {
+            let file = File::load(path)?;
+            file.check_project()?;
+            files.push(file);
+        }
+
+        let paths = files.iter().map(|file| file.path.clone()).collect();
+        let mut profiles = Vec::new();
+        let mut selected = Vec::new();
+        for file in files {
+            let File {
+                defaults,
+                profiles: mut named,
+                ..
+            } = file;
+            profiles.push(defaults);
+            if let Some(profile) = profile.and_then(|name| named.remove(name)) {
+                selected.push(profile);
+            }
+        }
+        if let (Some(name), true) = (profile, selected.is_empty()) {
+            return Err(Error::NoProfileError(name.to_owned()));
+        }
+        profiles.extend(selected);
+        Ok(Config { paths, profiles })
+    }
+
+    /// Sets the options in `opts` that weren't `given` on the command line
+    /// or with environment variables, so that the config files are the
+    /// lowest precedence defaults.
+    pub fn fill(&self, opts: &mut FetchOpts, given: &Given) {
+        let mut add = BTreeMap::new();
+        let mut vault_path_namespaces = BTreeMap::new();
+        for profile in &self.profiles {
+            profile.fill(opts, given);
+            add.extend(profile.add.clone());
+            vault_path_namespaces.extend(profile.vault_path_namespace.clone());
+        }
+        fill_pairs(&mut opts.add, add);
+        fill_pairs(&mut opts.vault_path_namespaces, vault_path_namespaces);
+    }
+
+    /// Sets `service` from the config files if it wasn't `given`.
+    pub fn fill_service(&self, service: &mut Option<String>, given: &Given) {
+        for profile in &self.profiles {
+            fill_some(service, &profile.service, given.service());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Opts, Subcommand};
+    use structopt::StructOpt;
+
+    /// Fills the options for `avvoenv write - <args>` from `profiles`, given
+    /// as TOML, lowest precedence first.
+    fn fill(profiles: &[&str], args: &[&str]) -> FetchOpts {
+        let config = Config {
+            paths: Vec::new(),
+            profiles: profiles
+                .iter()
+                .map(|s| toml::from_str(s).unwrap())
+                .collect(),
+        };
+        let args = ["avvoenv", "write", "-"].iter().chain(args);
+        let matches = Opts::clap().get_matches_from(args);
+        let mut opts = match Opts::from_clap(&matches).subcommand {
+            Some(Subcommand::Write(opts)) => opts.fetch,
+            _ => unreachable!(),
+        };
+        let given = Given::new(matches.subcommand_matches("write").unwrap());
+        config.fill(&mut opts, &given);
+        opts
+    }
+
+    #[test]
+    fn fills_unset_options() {
+        let opts = fill(&[r#"transit-mount = "config""#], &[]);
+        assert_eq!(opts.transit_mount, "config");
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let opts = fill(
+            &[r#"transit-mount = "config""#],
+            &["--transit-mount", "args"],
+        );
+        assert_eq!(opts.transit_mount, "args");
+    }
+
+    #[test]
+    fn later_profiles_take_precedence() {
+        let opts = fill(
+            &[
+                "pki-role = \"web\"\ninterpolate = true",
+                "interpolate = false",
+            ],
+            &[],
+        );
+        assert_eq!(opts.pki_role.as_deref(), Some("web"));
+        assert!(!opts.interpolate);
+    }
+
+    #[test]
+    fn given_auth_replaces_configured() {
+        let opts = fill(&[r#"role-id = "role""#], &["--dev"]);
+        assert!(opts.dev);
+        assert_eq!(opts.role_id, None);
+    }
+
+    #[test]
+    fn merges_add_by_key() {
+        let opts = fill(
+            &["[add]\nA = \"1\"\nB = \"2\"", "[add]\nA = \"3\""],
+            &["--add", "B=4"],
+        );
+        let add: Vec<_> = opts
+            .add
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_str()))
+            .collect();
+        assert_eq!(add, [("A", "3"), ("B", "4")]);
+    }
+
+    #[test]
+    fn parses_values_like_arguments() {
+        let opts = fill(&[r#"include = ["APP_*"]"#], &[]);
+        assert!(opts.include[0].matches("APP_NAME"));
+        assert!(toml::from_str::<Profile>(r#"consul = "not a url""#).is_err());
+    }
+
+    #[test]
+    fn project_files_cant_connect() {
+        let file = |defaults: &str, profile: &str| File {
+            path: PathBuf::from(PROJECT_FILE),
+            defaults: toml::from_str(defaults).unwrap(),
+            profiles: vec![("staging".to_owned(), toml::from_str(profile).unwrap())]
+                .into_iter()
+                .collect(),
+        };
+        assert!(file(r#"service = "app""#, r#"transit-key = "app""#)
+            .check_project()
+            .is_ok());
+        match file(
+            r#"service = "app""#,
+            "vault = \"https://vault\"\ndev = true",
+        )
+        .check_project()
+        {
+            Err(Error::ConnectionError { profile, keys, .. }) => {
+                assert_eq!(profile.as_deref(), Some("staging"));
+                assert_eq!(keys, ["vault", "dev"]);
+            }
+            result => panic!("{:?}", result),
+        }
+        assert!(file(r#"consul-token-file = "token""#, "")
+            .check_project()
+            .is_err());
+    }
+}
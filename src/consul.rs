@@ -1,20 +1,49 @@
-use std::{any::TypeId, fmt};
+use std::{any::TypeId, fmt, thread::sleep, time::Duration};
 
-use log::trace;
-use reqwest::Url;
-use serde_json::{from_value, json};
+use log::{debug, trace};
+use reqwest::{blocking::RequestBuilder, Url};
+use serde::Deserialize;
+use serde_json::{from_slice, from_value, json};
 
-use crate::client_error::ClientError;
+use crate::{client_error::ClientError, http, secret::Secret};
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Client {
     address: Url,
-    http: reqwest::blocking::Client,
+    token: Option<Secret>,
+    datacenters: Vec<String>,
+    namespace: Option<String>,
+    http: http::Client,
+}
+
+/// A blocking query against a single key, tracking the `X-Consul-Index` of
+/// the last response so that each query returns when the key changes.
+#[derive(Debug)]
+pub struct Watch {
+    client: Client,
+    key: String,
+    wait: Duration,
+    index: u64,
+    failures: u32,
+}
+
+/// A key as returned by a non-raw read, with its value base64 encoded.
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct Pair {
+    value: Option<String>,
+    modify_index: u64,
 }
 
 #[derive(Debug)]
 pub struct Error(ClientError);
 
+impl Error {
+    pub fn is_unavailable(&self) -> bool {
+        self.0.is_unavailable()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -34,10 +63,11 @@ impl<T: Into<ClientError>> From<T> for Error {
 }
 
 impl Client {
-    pub fn new(mut address: Url) -> Result<Client, Error> {
+    pub fn new(mut address: Url, tls: &http::Tls, retry: &http::Retry) -> Result<Client, Error> {
         if address.cannot_be_a_base() {
             return Err(ClientError::BaseUrlError(address).into());
         };
+        let http = http::client(&mut address, tls, retry)?;
         address
             .path_segments_mut()
             .expect("invalid base URL")
@@ -46,24 +76,69 @@ impl Client {
             .push("");
         Ok(Client {
             address,
-            http: reqwest::blocking::Client::new(),
+            token: None,
+            datacenters: Vec::new(),
+            namespace: None,
+            http,
         })
     }
-}
 
-impl crate::env::Client for Client {
-    type Error = Error;
+    pub fn token(&mut self, token: Secret) {
+        self.token = Some(token);
+    }
 
-    fn get<T>(&self, key: &str) -> Result<Option<T>, Error>
+    /// Set the datacenters to query, in order. Keys are read from the first,
+    /// unless the client is pinned to another with `in_datacenter_of`.
+    pub fn datacenters(&mut self, datacenters: Vec<String>) {
+        self.datacenters = datacenters;
+    }
+
+    /// A client that reads every key from the first datacenter that has
+    /// `key`, or the first datacenter if none do, so that keys that go
+    /// together all come from the same datacenter.
+    pub fn in_datacenter_of(&self, key: &str) -> Result<Client, Error> {
+        let mut client = self.clone();
+        for datacenter in &self.datacenters {
+            if let (Some(_), _) = self.get_index::<String>(key, Some(datacenter), None)? {
+                debug!("Using datacenter {:?}", datacenter);
+                client.datacenters = vec![datacenter.clone()];
+                return Ok(client);
+            }
+            debug!("{:?} not found in datacenter {:?}", key, datacenter);
+        }
+        client.datacenters.truncate(1);
+        Ok(client)
+    }
+
+    pub fn namespace(&mut self, namespace: String) {
+        self.namespace = Some(namespace);
+    }
+
+    /// Watch `key` for changes, with each blocking query waiting at most
+    /// `wait`. Only the first datacenter is watched.
+    pub fn watch(&self, key: &str, wait: Duration) -> Watch {
+        Watch {
+            client: self.clone(),
+            key: key.to_owned(),
+            wait,
+            index: 0,
+            failures: 0,
+        }
+    }
+
+    /// Gets a JSON value along with the `ModifyIndex` of its key, to pass to
+    /// `put` to only replace it if it hasn't changed since. Only the first
+    /// datacenter is read, as that's where `put` writes.
+    pub fn get_modify_index<T>(&self, key: &str) -> Result<Option<(T, u64)>, Error>
     where
-        T: serde::de::DeserializeOwned + 'static,
+        T: serde::de::DeserializeOwned,
     {
-        let mut url = self.address.join(key.trim_start_matches(|c| c == '/'))?;
-        url.set_query(Some("raw=true"));
-        let request = self.http.get(url.clone());
+        let url = self.url(key, self.datacenters.first().map(String::as_str))?;
+        let request = self.authorize(self.http.get(url.clone()));
         trace!("{:?}", request);
-        let response = request
-            .send()
+        let response = self
+            .http
+            .send(request)
             .map_err(|e| ClientError::with_url(url.clone(), e))?;
         trace!("{:?}", response);
         if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -72,13 +147,220 @@ impl crate::env::Client for Client {
         if !response.status().is_success() {
             return Err(ClientError::ServerError(response).into());
         }
+        let pairs: Vec<Pair> = response
+            .json()
+            .map_err(|e| ClientError::with_url(url.clone(), e))?;
+        let pair = match pairs.into_iter().next() {
+            Some(pair) => pair,
+            None => return Ok(None),
+        };
+        let value = base64::decode(pair.value.unwrap_or_default())
+            .map_err(|e| ClientError::with_url(url.clone(), e))?;
+        let value = from_slice(&value).map_err(|e| ClientError::with_url(url, e))?;
+        Ok(Some((value, pair.modify_index)))
+    }
+
+    /// Sets `key` to `value` as JSON in the first datacenter. With `cas` the
+    /// key is only set if its `ModifyIndex` is still `cas`, with 0 meaning it
+    /// mustn't exist yet. Returns whether the key was set.
+    pub fn put<T>(&self, key: &str, value: &T, cas: Option<u64>) -> Result<bool, Error>
+    where
+        T: serde::ser::Serialize,
+    {
+        let mut url = self.url(key, self.datacenters.first().map(String::as_str))?;
+        if let Some(cas) = cas {
+            url.query_pairs_mut().append_pair("cas", &cas.to_string());
+        }
+        let request = self.authorize(self.http.put(url.clone()).json(value));
+        trace!("{:?}", request);
+        let response = self
+            .http
+            .send(request)
+            .map_err(|e| ClientError::with_url(url.clone(), e))?;
+        trace!("{:?}", response);
+        if !response.status().is_success() {
+            return Err(ClientError::ServerError(response).into());
+        }
+        Ok(response.json().map_err(|e| ClientError::with_url(url, e))?)
+    }
+
+    /// The URL for `key` in `datacenter`, and the client's namespace.
+    fn url(&self, key: &str, datacenter: Option<&str>) -> Result<Url, Error> {
+        let mut url = self.address.join(key.trim_start_matches(|c| c == '/'))?;
+        if let Some(datacenter) = datacenter {
+            url.query_pairs_mut().append_pair("dc", datacenter);
+        }
+        if let Some(ref namespace) = self.namespace {
+            url.query_pairs_mut().append_pair("ns", namespace);
+        }
+        Ok(url)
+    }
+
+    fn authorize(&self, request: RequestBuilder) -> RequestBuilder {
+        match self.token {
+            Some(Secret(ref token)) => request.header("X-Consul-Token", token.as_str()),
+            None => request,
+        }
+    }
+
+    fn get_index<T>(
+        &self,
+        key: &str,
+        datacenter: Option<&str>,
+        index: Option<(u64, Duration)>,
+    ) -> Result<(Option<T>, Option<u64>), Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let mut url = self.url(key, datacenter)?;
+        url.query_pairs_mut().append_pair("raw", "true");
+        let mut timeout = None;
+        if let Some((index, wait)) = index {
+            let wait = wait.as_secs().max(1);
+            url.query_pairs_mut()
+                .append_pair("index", &index.to_string())
+                .append_pair("wait", &format!("{}s", wait));
+            // Consul adds up to wait / 16 jitter to the wait time
+            timeout = Some(Duration::from_secs(wait + wait / 16 + 10));
+        }
+        let mut request = self.authorize(self.http.get(url.clone()));
+        if let Some(timeout) = timeout {
+            request = request.timeout(timeout);
+        }
+        trace!("{:?}", request);
+        let response = self
+            .http
+            .send(request)
+            .map_err(|e| ClientError::with_url(url.clone(), e))?;
+        trace!("{:?}", response);
+        let index = response
+            .headers()
+            .get("X-Consul-Index")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok());
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok((None, index));
+        }
+        if !response.status().is_success() {
+            return Err(ClientError::ServerError(response).into());
+        }
         if TypeId::of::<String>() == TypeId::of::<T>() {
             let body = response
                 .text()
                 .map_err(|e| ClientError::with_url(url.clone(), e))?;
-            from_value(json!(body)).map_err(|e| ClientError::with_url(url, e).into())
+            let value = from_value(json!(body)).map_err(|e| ClientError::with_url(url, e))?;
+            Ok((Some(value), index))
         } else {
-            Ok(response.json().map_err(|e| ClientError::with_url(url, e))?)
+            let value = response.json().map_err(|e| ClientError::with_url(url, e))?;
+            Ok((Some(value), index))
         }
     }
 }
+
+impl crate::env::Client for Client {
+    type Error = Error;
+
+    fn get<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let datacenter = self.datacenters.first().map(String::as_str);
+        Ok(self.get_index(key, datacenter, None)?.0)
+    }
+}
+
+impl Watch {
+    /// Performs a single blocking query, returning `Some` with the new value
+    /// if the key changed, or `None` if the wait time elapsed without a
+    /// change. The first query returns the current value immediately.
+    ///
+    /// After an error the next query is delayed with an exponential backoff.
+    pub fn poll<T>(&mut self) -> Result<Option<Option<T>>, Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        if self.failures > 0 {
+            let backoff = self.client.http.backoff(self.failures);
+            debug!("Waiting {:?} before watching {:?}", backoff, self.key);
+            sleep(backoff);
+        }
+        let datacenter = self.client.datacenters.first().map(String::as_str);
+        let (value, index) =
+            match self
+                .client
+                .get_index(&self.key, datacenter, Some((self.index, self.wait)))
+            {
+                Ok(v) => v,
+                Err(e) => {
+                    self.failures = self.failures.saturating_add(1);
+                    return Err(e);
+                }
+            };
+        self.failures = 0;
+        let index = index.unwrap_or(0);
+        trace!("Watch of {:?} at index {}", self.key, index);
+        if index < self.index {
+            debug!("Consul index for {:?} went backwards, resetting", self.key);
+        }
+        let changed = index != self.index;
+        // an index of 0 would be a non-blocking query, so never use it
+        self.index = index.max(1);
+        Ok(if changed { Some(value) } else { None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        env::Client as _,
+        stub::{retry, Stub},
+    };
+
+    /// A stub Consul with datacenters `a` and `b`, where only `b` has the
+    /// config for `app`.
+    fn consul() -> (Stub, Client) {
+        let stub = Stub::new(|request| {
+            if request.path.contains("/app/") && request.path.contains("dc=b") {
+                (200, json!({"version": 1}))
+            } else {
+                (404, json!(null))
+            }
+        });
+        let mut client =
+            Client::new(stub.address.clone(), &http::Tls::default(), &retry()).unwrap();
+        client.datacenters(vec!["a".to_owned(), "b".to_owned()]);
+        (stub, client)
+    }
+
+    /// The datacenter each request was for.
+    fn datacenters(stub: &Stub) -> Vec<String> {
+        stub.requests()
+            .iter()
+            .map(|request| {
+                let url = stub.address.join(&request.path).unwrap();
+                let mut query = url.query_pairs();
+                query.find(|(key, _)| key == "dc").unwrap().1.into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn reads_from_one_datacenter() {
+        let (stub, client) = consul();
+        let client = client.in_datacenter_of("config/app/current").unwrap();
+        let global = client.get::<serde_json::Value>("config/global/current");
+        assert!(global.unwrap().is_none());
+        let app = client.get::<serde_json::Value>("config/app/1");
+        assert!(app.unwrap().is_some());
+        assert_eq!(datacenters(&stub), ["a", "b", "b", "b"]);
+    }
+
+    #[test]
+    fn falls_back_to_first_datacenter() {
+        let (stub, client) = consul();
+        let client = client.in_datacenter_of("config/other/current").unwrap();
+        client.get::<serde_json::Value>("config/other/1").unwrap();
+        assert_eq!(datacenters(&stub), ["a", "b", "a"]);
+    }
+}
@@ -0,0 +1,64 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    io::{self, Write},
+};
+
+use chacha20poly1305::aead::{rand_core::RngCore, OsRng};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+/// Writes the variables added, removed, and changed from `from` to `to`, one
+/// per line, sorted by name. Values are written as a prefix of their keyed
+/// hash, unless `reveal` is set. Returns whether there were any differences.
+pub fn to_writer<W: Write>(
+    mut writer: W,
+    from: &HashMap<String, String>,
+    to: &HashMap<String, String>,
+    reveal: bool,
+) -> io::Result<bool> {
+    let hasher = Hasher::random();
+    let show = |value: &str| {
+        if reveal {
+            format!("{:?}", value)
+        } else {
+            hasher.hash(value)
+        }
+    };
+    let keys: BTreeSet<_> = from.keys().chain(to.keys()).collect();
+    let mut changed = false;
+    for key in keys {
+        match (from.get(key), to.get(key)) {
+            (None, Some(new)) => writeln!(writer, "+ {}={}", key, show(new))?,
+            (Some(old), None) => writeln!(writer, "- {}={}", key, show(old))?,
+            (Some(old), Some(new)) if old != new => {
+                writeln!(writer, "~ {}={} -> {}", key, show(old), show(new))?
+            }
+            _ => continue,
+        }
+        changed = true;
+    }
+    Ok(changed)
+}
+
+/// Hashes values to show in place of values that may be secret. The hashes
+/// are keyed with an HMAC, with a key random to each `Hasher`, so that equal
+/// values can be spotted in one command's output, but short or guessable
+/// secrets can't be found by hashing candidates, or matched across runs.
+pub struct Hasher(Hmac<Sha256>);
+
+impl Hasher {
+    pub fn random() -> Hasher {
+        let mut key = [0; 32];
+        OsRng.fill_bytes(&mut key);
+        Hasher(Hmac::new_from_slice(&key).expect("HMAC takes keys of any length"))
+    }
+
+    /// A prefix of the keyed hash of `value`.
+    pub fn hash(&self, value: &str) -> String {
+        let mut mac = self.0.clone();
+        mac.update(value.as_bytes());
+        let digest = mac.finalize().into_bytes();
+        let hex: String = digest[..6].iter().map(|b| format!("{:02x}", b)).collect();
+        format!("hmac:{}", hex)
+    }
+}
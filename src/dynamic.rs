@@ -0,0 +1,220 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, Instant},
+};
+
+use log::{debug, info, trace, warn};
+use serde::Deserialize;
+
+use crate::vault;
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidDeclaration(String),
+    MissingField { path: String, field: String },
+    NotFound(String),
+    VaultError(vault::Error),
+}
+
+impl Error {
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Error::VaultError(e) => e.is_unavailable(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::InvalidDeclaration(name) => write!(
+                f,
+                "dynamic secret {:?} must have exactly one of field or template",
+                name
+            ),
+            Error::MissingField { path, field } => {
+                write!(f, "dynamic secret {:?} has no field {:?}", path, field)
+            }
+            Error::NotFound(path) => write!(f, "dynamic secret {:?} not found", path),
+            Error::VaultError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::InvalidDeclaration(_) | Error::MissingField { .. } | Error::NotFound(_) => None,
+            Error::VaultError(e) => Some(e),
+        }
+    }
+}
+
+impl From<vault::Error> for Error {
+    fn from(e: vault::Error) -> Error {
+        Error::VaultError(e)
+    }
+}
+
+/// Declares an environment variable built from a Vault dynamic secret, either
+/// a single `field` of the secret, or a `template` with fields substituted for
+/// `{{field}}`.
+#[derive(Debug, Deserialize)]
+pub struct Declaration {
+    path: String,
+    field: Option<String>,
+    template: Option<String>,
+}
+
+impl Declaration {
+    /// The path of the secret in Vault.
+    pub fn path(&self) -> &str {
+        self.path.trim_start_matches('/')
+    }
+}
+
+struct Lease {
+    id: String,
+    renewable: bool,
+    duration: Duration,
+    renew_at: Option<Instant>,
+    data: HashMap<String, String>,
+}
+
+impl fmt::Debug for Lease {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Lease")
+            .field("id", &self.id)
+            .field("renewable", &self.renewable)
+            .field("duration", &self.duration)
+            .field("renew_at", &self.renew_at)
+            .field("fields", &self.data.keys().collect::<Vec<_>>())
+            .finish()
+    }
+}
+
+/// Leases on dynamic secrets by path, so that each secret is only read once,
+/// and all the variables built from it get the same credentials.
+#[derive(Debug, Default)]
+pub struct Leases(HashMap<String, Lease>);
+
+impl Leases {
+    /// Returns the value of the environment variable `name`, reading the
+    /// secret if there isn't already a lease on it.
+    pub fn value(
+        &mut self,
+        client: &vault::Client,
+        name: &str,
+        declaration: &Declaration,
+    ) -> Result<String, Error> {
+        let path = declaration.path();
+        if !self.0.contains_key(path) {
+            self.0.insert(path.to_owned(), read(client, path)?);
+        }
+        let data = &self.0[path].data;
+        let field = |field: &str| {
+            data.get(field).ok_or_else(|| Error::MissingField {
+                path: path.to_owned(),
+                field: field.to_owned(),
+            })
+        };
+        match (&declaration.field, &declaration.template) {
+            (Some(name), None) => Ok(field(name)?.clone()),
+            (None, Some(template)) => {
+                let mut value = String::new();
+                let mut rest = template.as_str();
+                while let Some(start) = rest.find("{{") {
+                    let end = match rest[start..].find("}}") {
+                        Some(end) => start + end,
+                        None => break,
+                    };
+                    value.push_str(&rest[..start]);
+                    value.push_str(field(rest[start + 2..end].trim())?);
+                    rest = &rest[end + 2..];
+                }
+                value.push_str(rest);
+                Ok(value)
+            }
+            _ => Err(Error::InvalidDeclaration(name.to_owned())),
+        }
+    }
+
+    /// Renews the leases that have passed two thirds of their duration,
+    /// returning when the next lease is due for renewal. Leases that can't be
+    /// renewed, have reached their max TTL, or fail to renew, such as when
+    /// revoked along with the token that created them, are dropped so the
+    /// secret is read again the next time the environment is fetched.
+    pub fn renew(&mut self, client: &vault::Client) -> Option<Instant> {
+        let now = Instant::now();
+        let mut expiring = Vec::new();
+        for (path, lease) in self.0.iter_mut() {
+            match lease.renew_at {
+                Some(at) if at <= now => (),
+                _ => continue,
+            }
+            if !lease.renewable {
+                info!("Lease on {:?} can not be renewed", path);
+                expiring.push(path.clone());
+                continue;
+            }
+            debug!("Renewing lease on {:?}", path);
+            let duration = match client.renew_lease(&lease.id, lease.duration) {
+                Ok(duration) => duration,
+                Err(e) => {
+                    warn!("Could not renew lease on {:?}: {}", path, e);
+                    expiring.push(path.clone());
+                    continue;
+                }
+            };
+            if duration < lease.duration {
+                info!("Lease on {:?} expires in {:?}", path, duration);
+                expiring.push(path.clone());
+                continue;
+            }
+            lease.renew_at = Some(now + duration * 2 / 3);
+        }
+        for path in expiring {
+            self.0.remove(&path);
+        }
+        self.0.values().filter_map(|lease| lease.renew_at).min()
+    }
+
+    /// Revokes all the leases, so the credentials can no longer be used.
+    pub fn revoke(&mut self, client: &vault::Client) -> Result<(), Error> {
+        for (path, lease) in self.0.drain() {
+            debug!("Revoking lease on {:?}", path);
+            client.revoke_lease(&lease.id)?;
+        }
+        Ok(())
+    }
+}
+
+fn read(client: &vault::Client, path: &str) -> Result<Lease, Error> {
+    debug!("Reading dynamic secret {:?}", path);
+    let secret = client
+        .read_lease(path)?
+        .ok_or_else(|| Error::NotFound(path.to_owned()))?;
+    let duration = Duration::from_secs(secret.lease_duration);
+    let lease = Lease {
+        id: secret.lease_id,
+        renewable: secret.renewable,
+        duration,
+        renew_at: match secret.lease_duration {
+            0 => None,
+            _ => Some(Instant::now() + duration * 2 / 3),
+        },
+        data: secret
+            .data
+            .into_iter()
+            .map(|(key, value)| match value {
+                serde_json::Value::String(s) => (key, s),
+                serde_json::Value::Null => (key, String::new()),
+                value => (key, value.to_string()),
+            })
+            .collect(),
+    };
+    trace!("Got {:?}", lease);
+    Ok(lease)
+}
@@ -2,17 +2,25 @@ use std::{
     collections::HashMap,
     env, fmt,
     io::{self, Read},
-    path::PathBuf,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 use dirs::home_dir;
-use log::{debug, info, trace, warn};
-use serde::Deserialize;
+use glob::Pattern;
+use log::{debug, error, info, trace, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
-    consul,
+    cache::{self, Cache},
+    consul, dynamic,
+    explain::{Layer, Merged, Source},
+    http, interpolate, pki,
     prompt::{prompt_default, prompt_password},
-    rancher_metadata, service, vault, FetchOpts,
+    rancher_metadata,
+    schema::{self, Schema},
+    secret::{self, Secret},
+    service, vault, FetchOpts,
 };
 
 pub trait Client {
@@ -25,23 +33,63 @@ pub trait Client {
 
 #[derive(Debug)]
 pub enum Error {
+    CacheError(cache::Error),
+    ConflictError(String),
     ConsulError(consul::Error),
+    DecryptError { key: String, message: String },
+    DynamicError(dynamic::Error),
+    InterpolateError(interpolate::Error),
     IoError(io::Error),
+    PkiError(pki::Error),
     RancherError(rancher_metadata::Error),
+    SchemaError(schema::Error),
     ServiceError(service::Error),
     VaultError(vault::Error),
-    VaultTokenError(vault::ParseError),
+    SecretError(secret::ParseError),
+    WrappedTokenError { path: String, expected: Pattern },
+}
+
+impl Error {
+    /// Whether Consul, Vault, or Rancher could not be reached, as opposed to
+    /// refusing the request or returning data that doesn't check out.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Error::ConsulError(e) => e.is_unavailable(),
+            Error::DynamicError(e) => e.is_unavailable(),
+            Error::PkiError(e) => e.is_unavailable(),
+            Error::RancherError(e) => e.is_unavailable(),
+            Error::VaultError(e) => e.is_unavailable(),
+            _ => false,
+        }
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
+            Error::CacheError(e) => e.fmt(f),
+            Error::ConflictError(key) => {
+                write!(f, "{} was changed while publishing, try again", key)
+            }
             Error::ConsulError(e) => e.fmt(f),
+            Error::DecryptError { key, message } => {
+                write!(f, "unable to decrypt {}: {}", key, message)
+            }
+            Error::DynamicError(e) => e.fmt(f),
+            Error::InterpolateError(e) => e.fmt(f),
             Error::IoError(e) => e.fmt(f),
+            Error::PkiError(e) => e.fmt(f),
             Error::RancherError(e) => e.fmt(f),
+            Error::SchemaError(e) => e.fmt(f),
             Error::ServiceError(e) => e.fmt(f),
             Error::VaultError(e) => e.fmt(f),
-            Error::VaultTokenError(e) => e.fmt(f),
+            Error::SecretError(e) => e.fmt(f),
+            Error::WrappedTokenError { path, expected } => write!(
+                f,
+                "wrapped token was created at {:?}, expected {:?}",
+                path,
+                expected.as_str()
+            ),
         }
     }
 }
@@ -49,34 +97,72 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
         match self {
+            Error::CacheError(e) => Some(e),
+            Error::ConflictError(_) => None,
             Error::ConsulError(e) => Some(e),
+            Error::DecryptError { .. } => None,
+            Error::DynamicError(e) => Some(e),
+            Error::InterpolateError(e) => Some(e),
             Error::IoError(e) => Some(e),
+            Error::PkiError(e) => Some(e),
             Error::RancherError(e) => Some(e),
+            Error::SchemaError(e) => Some(e),
             Error::ServiceError(e) => Some(e),
             Error::VaultError(e) => Some(e),
-            Error::VaultTokenError(e) => Some(e),
+            Error::SecretError(e) => Some(e),
+            Error::WrappedTokenError { .. } => None,
         }
     }
 }
 
+impl From<cache::Error> for Error {
+    fn from(e: cache::Error) -> Error {
+        Error::CacheError(e)
+    }
+}
+
 impl From<consul::Error> for Error {
     fn from(e: consul::Error) -> Error {
         Error::ConsulError(e)
     }
 }
 
+impl From<dynamic::Error> for Error {
+    fn from(e: dynamic::Error) -> Error {
+        Error::DynamicError(e)
+    }
+}
+
+impl From<interpolate::Error> for Error {
+    fn from(e: interpolate::Error) -> Error {
+        Error::InterpolateError(e)
+    }
+}
+
 impl From<io::Error> for Error {
     fn from(e: io::Error) -> Error {
         Error::IoError(e)
     }
 }
 
+impl From<pki::Error> for Error {
+    fn from(e: pki::Error) -> Error {
+        Error::PkiError(e)
+    }
+}
+
 impl From<rancher_metadata::Error> for Error {
     fn from(e: rancher_metadata::Error) -> Error {
         Error::RancherError(e)
     }
 }
 
+impl From<schema::Error> for Error {
+    fn from(e: schema::Error) -> Error {
+        Error::SchemaError(e)
+    }
+}
+
 impl From<service::Error> for Error {
     fn from(e: service::Error) -> Error {
         Error::ServiceError(e)
@@ -89,123 +175,820 @@ impl From<vault::Error> for Error {
     }
 }
 
-impl From<vault::ParseError> for Error {
-    fn from(e: vault::ParseError) -> Error {
-        Error::VaultTokenError(e)
+impl From<secret::ParseError> for Error {
+    fn from(e: secret::ParseError) -> Error {
+        Error::SecretError(e)
     }
 }
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Serialize)]
 struct VersionInfo {
     version: u64,
 }
 
+/// Changes to publish to a service's config or secrets.
+#[derive(Debug, Default)]
+pub(crate) struct Changes {
+    pub(crate) set: Vec<(String, String)>,
+    pub(crate) unset: Vec<String>,
+}
+
+impl Changes {
+    /// Applies the changes to `map`, returning whether it changed.
+    fn apply(&self, map: &mut HashMap<String, String>) -> bool {
+        let mut changed = false;
+        for key in &self.unset {
+            changed |= map.remove(key).is_some();
+        }
+        for (key, value) in &self.set {
+            changed |= map.insert(key.clone(), value.clone()).as_ref() != Some(value);
+        }
+        changed
+    }
+}
+
+/// The versions written by publishing, `None` where nothing changed.
+pub(crate) struct Published {
+    pub(crate) config: Option<u64>,
+    pub(crate) secrets: Option<u64>,
+}
+
 pub(crate) fn fetch(opts: FetchOpts) -> Result<HashMap<String, String>, Error> {
-    let mut env = HashMap::new();
-    let service = service::name(opts.service)?;
-    info!("Fetching environment for {}", service);
-
-    let consul = consul::Client::new(opts.consul)?;
-    trace!("Configured Consul: {:?}", consul);
-    let mut vault = vault::Client::new(opts.vault)?;
-    trace!("Configured Vault: {:?}", vault);
-
-    if opts.dev {
-        info!("Authenticating with Vault via LDAP");
-        let user = prompt_default("Vault username: ", env::var("USER").ok())?;
-        let password = prompt_password("Vault password: ")?;
-        vault.ldap_auth(&user, &password)?;
-    } else if let (Some(app_id), Some(app_user)) = (&opts.app_id, &opts.app_user) {
-        debug!("Authenticating with Vault via App ID");
-        vault.app_id_auth(app_id, app_user)?;
-    } else if let Some(token) = opts.token {
-        debug!("Using supplied Vault token");
-        vault.token(token);
-    } else {
-        debug!("Using Vault token from ~/.vault-token");
-        let mut path = home_dir().unwrap_or_else(|| PathBuf::from("/"));
-        path.push(".vault-token");
-        let f = std::fs::File::open(path)?;
-        let mut reader = std::io::BufReader::new(f);
-        let mut string = String::new();
-        reader.read_to_string(&mut string)?;
-        vault.token(string.trim().parse()?);
-    }
-
-    if !opts.skip_rancher_metadata
-        && !skip_rancher_metadata_env()
-        && rancher_metadata::is_available()
-    {
-        debug!("Fetching config from Rancher");
-        let rancher = rancher_metadata::Client::new();
-        if let Some(info) = rancher.info()? {
-            let map: HashMap<_, _> = info.into_iter().collect();
-            trace!("Merging to environment: {:?}", map);
-            env.extend(map);
+    let cache = cache(&opts)?;
+    Fetcher::new(opts)
+        .and_then(|mut fetcher| fetcher.fetch())
+        .or_else(|e| fallback(e, cache.as_ref()))
+}
+
+/// Fetches the environment to print the values of `keys`, as with `fetch`.
+pub(crate) fn get(opts: FetchOpts, keys: &[String]) -> Result<HashMap<String, String>, Error> {
+    let cache = cache(&opts)?;
+    Fetcher::new(opts)
+        .and_then(|mut fetcher| fetcher.get(keys))
+        .or_else(|e| fallback(e, cache.as_ref()))
+}
+
+/// Fetches the environment to look at, rather than to run the service with,
+/// so without dynamic secrets or the certificate.
+pub(crate) fn inspect(opts: FetchOpts) -> Result<HashMap<String, String>, Error> {
+    let cache = cache(&opts)?;
+    Fetcher::new(opts)
+        .and_then(|mut fetcher| {
+            fetcher.inspect_only();
+            fetcher.fetch()
+        })
+        .or_else(|e| fallback(e, cache.as_ref()))
+}
+
+/// Fetches the environment with where each variable came from, without
+/// dynamic secrets or the certificate. There's no falling back to the cache, as it doesn't
+/// record that.
+pub(crate) fn explain(opts: FetchOpts) -> Result<Merged, Error> {
+    let mut fetcher = Fetcher::new(opts)?;
+    fetcher.inspect_only();
+    fetcher.explain()
+}
+
+/// The cache of the environment fetched with `opts`, if `--cache-dir` is set.
+pub(crate) fn cache(opts: &FetchOpts) -> Result<Option<Cache>, Error> {
+    let dir = match opts.cache_dir {
+        Some(ref dir) => dir,
+        None => return Ok(None),
+    };
+    let service = service::name(opts.service.clone())?;
+    let key = match (&opts.cache_key_file, &opts.token) {
+        (Some(path), _) => read_secret(path)?,
+        (None, Some(token)) => token.clone(),
+        (None, None) => return Err(cache::Error::NoKeyError.into()),
+    };
+    let max_age = Duration::from_secs(opts.cache_max_age);
+    Ok(Some(Cache::new(dir, &service, &key, max_age)))
+}
+
+/// Falls back to the cached environment after `error` fetching it, or
+/// returns `error` if there's nothing usable in the cache.
+pub(crate) fn fallback(
+    error: Error,
+    cache: Option<&Cache>,
+) -> Result<HashMap<String, String>, Error> {
+    let cache = match cache {
+        Some(cache) if error.is_unavailable() => cache,
+        _ => return Err(error),
+    };
+    match cache.load() {
+        Ok(Some(entry)) => {
+            debug!("{:?}", error);
+            error!("{}", error);
+            error!(
+                "Using the environment cached {} seconds ago",
+                entry.age().as_secs()
+            );
+            Ok(entry.env)
+        }
+        Ok(None) => Err(error),
+        Err(e) => {
+            warn!("Could not read the cached environment: {}", e);
+            Err(error)
+        }
+    }
+}
+
+/// The outcome of renewing what a `Fetcher` holds.
+pub(crate) struct Renewal {
+    /// When to next renew, or `None` if nothing expires.
+    pub(crate) next: Option<Instant>,
+    /// Whether the certificate was reissued, rewriting its files.
+    pub(crate) reissued: bool,
+    /// The errors renewing the token or the certificate, each of which is
+    /// renewed regardless of the others failing.
+    pub(crate) errors: Vec<Error>,
+}
+
+/// Authenticated Consul and Vault clients, along with the options needed to
+/// resolve the environment for a service, so the environment can be fetched
+/// again without re-authenticating or reading new dynamic secrets.
+#[derive(Debug)]
+pub(crate) struct Fetcher {
+    service: String,
+    consul: consul::Client,
+    vault: vault::Client,
+    token_renewal: Option<Instant>,
+    /// Whether to read dynamic secrets and issue the certificate, which
+    /// create new credentials.
+    dynamic: bool,
+    leases: dynamic::Leases,
+    certificate: Option<pki::Certificate>,
+    transit_mount: String,
+    transit_key: String,
+    interpolate: bool,
+    interpolate_strict: bool,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    add: Vec<(String, String)>,
+    secrets_version: Option<u64>,
+    config_version: Option<u64>,
+    skip_rancher_metadata: bool,
+    retry: http::Retry,
+    cache: Option<Cache>,
+    schema: Option<Schema>,
+}
+
+impl Fetcher {
+    pub(crate) fn new(opts: FetchOpts) -> Result<Fetcher, Error> {
+        if let Some(ref profile) = opts.profile {
+            debug!("Using profile {:?}", profile);
+        }
+        let cache = cache(&opts)?;
+        let service = service::name(opts.service)?;
+
+        let retry = http::Retry {
+            connect_timeout: Duration::from_secs(opts.http_connect_timeout),
+            timeout: Duration::from_secs(opts.http_timeout),
+            max_attempts: opts.http_max_attempts.max(1),
+            max_backoff: Duration::from_secs(opts.http_max_backoff),
+        };
+        let consul_tls = http::Tls {
+            ca_cert: opts.consul_ca_cert,
+            ca_path: opts.consul_ca_path,
+            client_cert: opts.consul_client_cert,
+            client_key: opts.consul_client_key,
+            skip_verify: opts.consul_skip_verify
+                || !env_flag("CONSUL_HTTP_SSL_VERIFY").unwrap_or(true),
+            server_name: opts.consul_tls_server_name,
+        };
+        let mut consul = consul::Client::new(
+            opts.consul.expect("--consul is required"),
+            &consul_tls,
+            &retry,
+        )?;
+        if let Some(path) = opts.consul_token_file {
+            debug!("Using Consul token from {:?}", path);
+            consul.token(read_secret(path)?);
+        } else if let Some(token) = opts.consul_token {
+            debug!("Using supplied Consul token");
+            consul.token(token);
+        }
+        if !opts.consul_datacenters.is_empty() {
+            debug!("Using Consul datacenters {:?}", opts.consul_datacenters);
+            consul.datacenters(opts.consul_datacenters);
+        }
+        if let Some(namespace) = opts.consul_namespace {
+            debug!("Using Consul namespace {:?}", namespace);
+            consul.namespace(namespace);
+        }
+        trace!("Configured Consul: {:?}", consul);
+        let vault_tls = http::Tls {
+            ca_cert: opts.vault_ca_cert,
+            ca_path: opts.vault_ca_path,
+            client_cert: opts.vault_client_cert,
+            client_key: opts.vault_client_key,
+            skip_verify: opts.vault_skip_verify || env_flag("VAULT_SKIP_VERIFY").unwrap_or(false),
+            server_name: opts.vault_tls_server_name,
+        };
+        let mut vault =
+            vault::Client::new(opts.vault.expect("--vault is required"), &vault_tls, &retry)?;
+        if let Some(namespace) = opts.vault_namespace {
+            debug!("Using Vault namespace {:?}", namespace);
+            vault.namespace(namespace);
+        }
+        for (path, namespace) in opts.vault_path_namespaces {
+            debug!("Using Vault namespace {:?} for {:?}", namespace, path);
+            vault.path_namespace(&path, namespace);
+        }
+        trace!("Configured Vault: {:?}", vault);
+
+        if opts.dev {
+            info!("Authenticating with Vault via LDAP");
+            let user = prompt_default("Vault username: ", env::var("USER").ok())?;
+            let password = prompt_password("Vault password: ")?;
+            vault.ldap_auth(&user, Secret(password))?;
+        } else if let Some(role_id) = opts.role_id {
+            debug!("Authenticating with Vault via AppRole");
+            let secret_id = match opts.secret_id_file {
+                Some(path) => Some(read_secret(path)?),
+                None => opts.secret_id,
+            };
+            vault.approle_auth(&role_id, secret_id)?;
+        } else if let Some(role) = opts.kubernetes_role {
+            debug!("Authenticating with Vault via Kubernetes");
+            let jwt = read_secret(opts.kubernetes_token_file)?;
+            vault.kubernetes_auth(&opts.kubernetes_mount, &role, jwt)?;
+        } else if let (Some(app_id), Some(app_user)) = (&opts.app_id, &opts.app_user) {
+            debug!("Authenticating with Vault via App ID");
+            vault.app_id_auth(app_id, app_user)?;
+        } else if let Some(wrapped_token) = opts.wrapped_token {
+            debug!("Unwrapping Vault token");
+            let info = vault.wrap_info(&wrapped_token)?;
+            trace!("{:?}", info);
+            if !opts.wrapped_path.matches(&info.creation_path) {
+                return Err(Error::WrappedTokenError {
+                    path: info.creation_path,
+                    expected: opts.wrapped_path,
+                });
+            }
+            vault.unwrap_token(wrapped_token)?;
+        } else if let Some(token) = opts.token {
+            debug!("Using supplied Vault token");
+            vault.token(token);
+        } else {
+            debug!("Using Vault token from ~/.vault-token");
+            let mut path = home_dir().unwrap_or_else(|| PathBuf::from("/"));
+            path.push(".vault-token");
+            vault.token(read_secret(path)?);
+        }
+
+        let transit_key = opts.transit_key.unwrap_or_else(|| service.clone());
+        let schema = match opts.schema {
+            _ if opts.no_schema => None,
+            Some(path) => Some(Schema::load(&path)?),
+            None if Path::new(schema::DEFAULT_FILE).is_file() => {
+                Some(Schema::load(Path::new(schema::DEFAULT_FILE))?)
+            }
+            None => None,
+        };
+        let interpolate_strict =
+            opts.interpolate_strict || env_flag("AVVOENV_INTERPOLATE_STRICT").unwrap_or(false);
+        let interpolate = interpolate_strict
+            || opts.interpolate
+            || env_flag("AVVOENV_INTERPOLATE").unwrap_or(false);
+        let certificate = match opts.pki_role {
+            Some(role) => Some(pki::Certificate::new(
+                &opts.pki_mount,
+                role,
+                opts.pki_common_name.unwrap_or_else(|| service.clone()),
+                opts.pki_cert_file,
+                opts.pki_key_file,
+                opts.pki_ca_file,
+            )),
+            None => None,
+        };
+
+        Ok(Fetcher {
+            service,
+            consul,
+            vault,
+            token_renewal: Some(Instant::now()),
+            dynamic: true,
+            leases: dynamic::Leases::default(),
+            certificate,
+            transit_mount: opts.transit_mount.trim_matches('/').to_owned(),
+            transit_key,
+            interpolate,
+            interpolate_strict,
+            include: opts.include,
+            exclude: opts.exclude,
+            add: opts.add,
+            secrets_version: opts.secrets_version,
+            config_version: opts.config_version,
+            skip_rancher_metadata: opts.skip_rancher_metadata,
+            retry,
+            cache,
+            schema,
+        })
+    }
+
+    /// Don't read dynamic secrets or issue the certificate, for commands that
+    /// only look at the environment, as each creates credentials that would
+    /// never be used or revoked, and issuing the certificate overwrites its
+    /// files. The environment also isn't cached, as it's incomplete.
+    pub(crate) fn inspect_only(&mut self) {
+        self.dynamic = false;
+    }
+
+    /// Watch the service's current config version in Consul for changes.
+    pub(crate) fn watch(&self, wait: Duration) -> consul::Watch {
+        self.consul
+            .watch(&format!("config/{}/current", self.service), wait)
+    }
+
+    /// Renew the Vault token, dynamic secret leases, and certificate that are
+    /// due for renewal.
+    pub(crate) fn renew(&mut self) -> Renewal {
+        let now = Instant::now();
+        let mut errors = Vec::new();
+        if let Some(at) = self.token_renewal {
+            if at <= now {
+                match self.vault.renew_token() {
+                    Ok(ttl) => self.token_renewal = ttl.map(|ttl| now + ttl * 2 / 3),
+                    Err(e) => errors.push(e.into()),
+                }
+            }
+        }
+        let leases = self.leases.renew(&self.vault);
+        let mut reissued = false;
+        let mut next = [self.token_renewal, leases].iter().flatten().min().cloned();
+        if let Some(ref mut certificate) = self.certificate {
+            match certificate.renew(&self.vault) {
+                Ok(renewed) => reissued = renewed,
+                Err(e) => errors.push(e.into()),
+            }
+            next = next.into_iter().chain(certificate.renew_at()).min();
+        }
+        Renewal {
+            next,
+            reissued,
+            errors,
         }
     }
 
-    debug!("Fetching global config");
-    fill(&mut env, &consul, "global")?;
-    debug!("Fetching global secrets");
-    fill(&mut env, &vault, "global")?;
+    /// The wait before renewing again after `failures` failures in a row.
+    pub(crate) fn backoff(&self, failures: u32) -> Duration {
+        self.retry.backoff(failures)
+    }
+
+    /// Revoke any dynamic secret leases, and the Vault token if it was created
+    /// by avvoenv.
+    pub(crate) fn revoke(&mut self) -> Result<(), Error> {
+        self.leases.revoke(&self.vault)?;
+        Ok(self.vault.revoke_token()?)
+    }
 
-    debug!("Fetching {} dependencies", service);
-    fill_dependencies(&mut env, &consul, &service)?;
-    debug!("Fetching {} generated", service);
-    fill_generated(&mut env, &consul, &service)?;
+    /// Publishes a new version of the service's Consul config and Vault
+    /// secrets with `config` and `secrets` applied, stamped with `user`.
+    /// Variables set in `config` that are already in the service's secrets
+    /// are set in the secrets instead, so that a secret's new value isn't
+    /// written to Consul in plain text.
+    pub(crate) fn publish(
+        &self,
+        config: &Changes,
+        secrets: &Changes,
+        user: &str,
+    ) -> Result<Published, Error> {
+        info!("Publishing environment for {}", self.service);
+        let mut current = Merged::default();
+        fill_secrets(
+            &mut current,
+            &self.vault,
+            &self.service,
+            None,
+            Layer::Secrets,
+        )?;
+        let (moved, set): (Vec<_>, Vec<_>) = config
+            .set
+            .iter()
+            .cloned()
+            .partition(|(key, _)| current.contains(key));
+        for (key, _) in &moved {
+            info!("Setting {} in the secrets, as it's already a secret", key);
+        }
+        let config = Changes {
+            set,
+            unset: config.unset.clone(),
+        };
+        let secrets = Changes {
+            set: moved
+                .into_iter()
+                .chain(secrets.set.iter().cloned())
+                .collect(),
+            unset: secrets.unset.clone(),
+        };
+        Ok(Published {
+            config: publish(&self.consul, &self.service, &config, user)?,
+            secrets: publish_secrets(&self.vault, &self.service, &secrets, user)?,
+        })
+    }
 
-    debug!("Fetching {} config", service);
-    fill(&mut env, &consul, &service)?;
-    debug!("Fetching {} secrets", service);
-    fill(&mut env, &vault, &service)?;
+    pub(crate) fn fetch(&mut self) -> Result<HashMap<String, String>, Error> {
+        let env = self.resolve()?.into_map();
+        self.save(&env);
+        Ok(env)
+    }
 
-    let include = opts.include;
-    let exclude = opts.exclude;
-    env.retain(|key, _| {
-        let keep = (include.is_empty() || include.iter().any(|p| p.matches(key)))
-            && !exclude.iter().any(|p| p.matches(key));
-        if !keep {
-            trace!("Filtering out {:?}", key);
+    /// Fetches the environment to print the values of `keys`. If none of
+    /// them come from a dynamic secret the leases are revoked, as the
+    /// credentials would never be used.
+    pub(crate) fn get(&mut self, keys: &[String]) -> Result<HashMap<String, String>, Error> {
+        let env = self.resolve()?;
+        if keys.iter().any(|key| env.comes_from(key, Layer::Dynamic)) {
+            let env = env.into_map();
+            self.save(&env);
+            return Ok(env);
         }
-        keep
-    });
+        self.leases.revoke(&self.vault)?;
+        Ok(env.into_map())
+    }
 
-    trace!("Merging to environment from options: {:?}", opts.add);
-    env.extend(opts.add);
+    /// Fetches the environment, validated, filtered, and decrypted.
+    fn resolve(&mut self) -> Result<Merged, Error> {
+        let mut env = self.merge()?;
+        if let Some(ref schema) = self.schema {
+            let values = env.values().map(|(k, v)| (k.clone(), v.clone())).collect();
+            schema.validate(&values)?;
+        }
+        self.filter(&mut env);
+        self.decrypt(&mut env)?;
+        Ok(env)
+    }
 
-    Ok(env)
+    /// Caches `env`, if it's complete.
+    fn save(&self, env: &HashMap<String, String>) {
+        if let (true, Some(cache)) = (self.dynamic, &self.cache) {
+            if let Err(e) = cache.save(env) {
+                warn!("Could not cache the environment: {}", e);
+            }
+        }
+    }
+
+    /// Fetches the environment, keeping where each variable came from, and
+    /// the values it shadowed.
+    pub(crate) fn explain(&mut self) -> Result<Merged, Error> {
+        let mut env = self.merge()?;
+        self.filter(&mut env);
+        self.decrypt(&mut env)?;
+        Ok(env)
+    }
+
+    /// Fetches and merges every layer of the environment, before the fetched
+    /// variables are filtered.
+    fn merge(&mut self) -> Result<Merged, Error> {
+        let mut env = Merged::default();
+        let service = &self.service;
+        info!("Fetching environment for {}", service);
+        let consul = self
+            .consul
+            .in_datacenter_of(&format!("config/{}/current", service))?;
+
+        if !self.skip_rancher_metadata
+            && !skip_rancher_metadata_env()
+            && rancher_metadata::is_available()
+        {
+            debug!("Fetching config from Rancher");
+            let rancher = rancher_metadata::Client::new(&self.retry)?;
+            if let Some(info) = rancher.info()? {
+                let map: HashMap<_, _> = info.into_iter().collect();
+                trace!("Merging to environment: {:?}", map);
+                env.extend(map, &Source::new(Layer::Rancher));
+            }
+        }
+
+        debug!("Fetching global config");
+        fill(&mut env, &consul, "global", None, Layer::GlobalConfig)?;
+        debug!("Fetching global secrets");
+        fill_secrets(&mut env, &self.vault, "global", None, Layer::GlobalSecrets)?;
+
+        debug!("Fetching {} dependencies", service);
+        fill_dependencies(&mut env, &consul, service)?;
+        debug!("Fetching {} generated", service);
+        fill_generated(&mut env, &consul, service)?;
+
+        debug!("Fetching {} config", service);
+        fill(
+            &mut env,
+            &consul,
+            service,
+            self.config_version,
+            Layer::Config,
+        )?;
+        debug!("Fetching {} secrets", service);
+        fill_secrets(
+            &mut env,
+            &self.vault,
+            service,
+            self.secrets_version,
+            Layer::Secrets,
+        )?;
+        if self.dynamic {
+            debug!("Fetching {} dynamic secrets", service);
+            fill_dynamic(&mut env, &consul, &self.vault, &mut self.leases, service)?;
+        } else {
+            debug!("Skipping {} dynamic secrets", service);
+        }
+
+        if let (true, Some(certificate)) = (self.dynamic, &mut self.certificate) {
+            let paths = certificate.env(&self.vault)?;
+            trace!("Merging to environment: {:?}", paths);
+            let source = Source::path(Layer::Certificate, certificate.issue_path(), None);
+            env.extend(paths, &source);
+        }
+
+        trace!("Merging to environment from options: {:?}", self.add);
+        env.extend(self.add.iter().cloned(), &Source::new(Layer::Add));
+
+        if let Some(ref schema) = self.schema {
+            let defaults = schema.defaults(|key| env.contains(key));
+            trace!("Merging defaults to environment: {:?}", defaults);
+            let path = schema.path().to_string_lossy();
+            env.extend(defaults, &Source::path(Layer::Default, path, None));
+        }
+
+        // references can be to variables that are filtered out, and the schema
+        // checks them too, so in those cases everything is decrypted up front
+        // rather than only what's left after filtering
+        if self.interpolate || self.schema.is_some() {
+            self.decrypt(&mut env)?;
+        }
+        if self.interpolate {
+            debug!("Interpolating references");
+            let (templates, literals) = env.templates();
+            let interpolated =
+                interpolate::interpolate(&templates, &literals, self.interpolate_strict)?;
+            for (key, interpolated) in interpolated {
+                env.interpolated(&key, interpolated.value, interpolated.references);
+            }
+        }
+
+        Ok(env)
+    }
+
+    /// Filters the fetched variables with `--include` and `--exclude`. Those
+    /// set with `--add` and the certificate paths aren't fetched, so are
+    /// kept.
+    fn filter(&self, env: &mut Merged) {
+        let include = &self.include;
+        let exclude = &self.exclude;
+        env.retain(|key, layer| {
+            if let Layer::Add | Layer::Certificate = layer {
+                return true;
+            }
+            let keep = (include.is_empty() || include.iter().any(|p| p.matches(key)))
+                && !exclude.iter().any(|p| p.matches(key));
+            if !keep {
+                trace!("Filtering out {:?}", key);
+            }
+            keep
+        });
+    }
+
+    fn decrypt(&self, env: &mut Merged) -> Result<(), Error> {
+        decrypt(env, &self.vault, &self.transit_mount, &self.transit_key)
+    }
 }
 
-fn fill<T>(env: &mut HashMap<String, String>, client: &T, service: &str) -> Result<(), Error>
+fn fill<T>(
+    env: &mut Merged,
+    client: &T,
+    service: &str,
+    version: Option<u64>,
+    layer: Layer,
+) -> Result<(), Error>
 where
     T: Client,
     Error: From<<T as Client>::Error>,
 {
-    let version = client
-        .get::<VersionInfo>(&format!("config/{}/current", service))?
-        .map(|v| v.version)
-        .unwrap_or_else(|| {
-            warn!("could not determine version, using 1");
-            1
-        });
+    let version = match version {
+        Some(v) => v,
+        None => client
+            .get::<VersionInfo>(&format!("config/{}/current", service))?
+            .map(|v| v.version)
+            .unwrap_or_else(|| {
+                warn!("could not determine version, using 1");
+                1
+            }),
+    };
     debug!("Got version {}", version);
-    if let Some(mut map) =
-        client.get::<HashMap<String, String>>(&format!("config/{}/{}", service, version))?
-    {
+    let key = format!("config/{}/{}", service, version);
+    if let Some(mut map) = client.get::<HashMap<String, String>>(&key)? {
         map.remove("__timestamp__");
         map.remove("__user__");
         trace!("Merging to environment: {:?}", map);
-        env.extend(map);
+        env.extend(map, &Source::path(layer, key, Some(version)));
     };
     Ok(())
 }
 
-fn fill_dependencies(
-    env: &mut HashMap<String, String>,
+/// Fills secrets from Vault. On KV version 2 mounts secrets are read from
+/// `config/<service>` using Vault's own versioning, falling back to the
+/// `config/<service>/current` version pointer if that doesn't exist.
+fn fill_secrets(
+    env: &mut Merged,
+    client: &vault::Client,
+    service: &str,
+    version: Option<u64>,
+    layer: Layer,
+) -> Result<(), Error> {
+    let key = format!("config/{}", service);
+    if client.kv_version(&key)? == 2 {
+        if let Some((mut map, got)) =
+            client.get_versioned::<HashMap<String, String>>(&key, version)?
+        {
+            debug!("Got KV version 2 secrets for {}", service);
+            map.remove("__timestamp__");
+            map.remove("__user__");
+            trace!("Merging to environment: {:?}", map);
+            env.extend(map, &Source::path(layer, key, got.or(version)));
+            return Ok(());
+        }
+        debug!("No KV version 2 secrets at {:?}", key);
+    }
+    fill(env, client, service, version, layer)
+}
+
+/// Publishes a new version of the service's config to Consul, the inverse of
+/// `fill`. The new version is written to the first unused key after the
+/// current version, and then `config/<service>/current` is pointed at it,
+/// only if it hasn't changed since it was read.
+fn publish(
     client: &consul::Client,
+    service: &str,
+    changes: &Changes,
+    user: &str,
+) -> Result<Option<u64>, Error> {
+    let current_key = format!("config/{}/current", service);
+    let (current, index) = match client.get_modify_index::<VersionInfo>(&current_key)? {
+        Some((info, index)) => (Some(info.version), index),
+        None => (None, 0),
+    };
+    let key = format!("config/{}/{}", service, current.unwrap_or(1));
+    let mut map = client
+        .get_modify_index::<HashMap<String, String>>(&key)?
+        .map(|(map, _)| map)
+        .unwrap_or_default();
+    if !changes.apply(&mut map) {
+        debug!("No changes to config for {}", service);
+        return Ok(None);
+    }
+    stamp(&mut map, user);
+    let mut version = current.map_or(1, |v| v + 1);
+    while !client.put(&format!("config/{}/{}", service, version), &map, Some(0))? {
+        debug!("config/{}/{} already exists", service, version);
+        version += 1;
+    }
+    if !client.put(&current_key, &VersionInfo { version }, Some(index))? {
+        return Err(Error::ConflictError(current_key));
+    }
+    info!("Published config/{}/{}", service, version);
+    Ok(Some(version))
+}
+
+/// Publishes a new version of the service's secrets to Vault, the inverse of
+/// `fill_secrets`. On KV version 2 mounts this is a new version of
+/// `config/<service>`, unless the service only has secrets under the
+/// `config/<service>/current` version pointer.
+fn publish_secrets(
+    client: &vault::Client,
+    service: &str,
+    changes: &Changes,
+    user: &str,
+) -> Result<Option<u64>, Error> {
+    let key = format!("config/{}", service);
+    let current_key = format!("config/{}/current", service);
+    if client.kv_version(&key)? == 2 {
+        let latest = client.get_versioned::<HashMap<String, String>>(&key, None)?;
+        if latest.is_some() || client.get::<VersionInfo>(&current_key)?.is_none() {
+            let (mut map, version) = match latest {
+                Some((map, version)) => (map, version.unwrap_or(0)),
+                None => (HashMap::new(), 0),
+            };
+            if !changes.apply(&mut map) {
+                debug!("No changes to secrets for {}", service);
+                return Ok(None);
+            }
+            stamp(&mut map, user);
+            if !client.put_version(&key, &map, Some(version))? {
+                return Err(Error::ConflictError(key));
+            }
+            info!("Published {} version {}", key, version + 1);
+            return Ok(Some(version + 1));
+        }
+    }
+
+    let current = client.get::<VersionInfo>(&current_key)?.map(|v| v.version);
+    let mut map = client
+        .get::<HashMap<String, String>>(&format!("{}/{}", key, current.unwrap_or(1)))?
+        .unwrap_or_default();
+    if !changes.apply(&mut map) {
+        debug!("No changes to secrets for {}", service);
+        return Ok(None);
+    }
+    stamp(&mut map, user);
+    let mut version = current.map_or(1, |v| v + 1);
+    while client
+        .get::<HashMap<String, String>>(&format!("{}/{}", key, version))?
+        .is_some()
+    {
+        debug!("{}/{} already exists", key, version);
+        version += 1;
+    }
+    // Vault has no check-and-set for KV version 1, so this can race
+    client.put_version(&format!("{}/{}", key, version), &map, None)?;
+    client.put_version(&current_key, &VersionInfo { version }, None)?;
+    info!("Published {}/{}", key, version);
+    Ok(Some(version))
+}
+
+/// Records who published a version of the config or secrets, and when.
+fn stamp(map: &mut HashMap<String, String>, user: &str) {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    map.insert("__user__".to_owned(), user.to_owned());
+    map.insert("__timestamp__".to_owned(), timestamp.to_string());
+}
+
+fn fill_dynamic(
+    env: &mut Merged,
+    consul: &consul::Client,
+    vault: &vault::Client,
+    leases: &mut dynamic::Leases,
     app: &str,
 ) -> Result<(), Error> {
+    let declarations = match consul
+        .get::<HashMap<String, dynamic::Declaration>>(&format!("config/{}/dynamic", app))?
+    {
+        Some(v) => v,
+        None => return Ok(()),
+    };
+    trace!("Got dynamic secrets: {:?}", declarations);
+    for (key, declaration) in declarations {
+        let value = leases.value(vault, &key, &declaration)?;
+        trace!("Merging dynamic secret to environment: {:?}", key);
+        let source = Source::path(Layer::Dynamic, declaration.path(), None);
+        env.insert(key, value, &source);
+    }
+    Ok(())
+}
+
+/// Decrypts any values that are Vault Transit ciphertext, such as
+/// `vault:v1:...`, in a single request.
+fn decrypt(env: &mut Merged, client: &vault::Client, mount: &str, key: &str) -> Result<(), Error> {
+    let encrypted: Vec<(&String, &String)> = env
+        .values()
+        .filter(|(_, value)| is_ciphertext(value))
+        .collect();
+    if encrypted.is_empty() {
+        return Ok(());
+    }
+    debug!(
+        "Decrypting {} values with {}/{}",
+        encrypted.len(),
+        mount,
+        key
+    );
+    let ciphertexts: Vec<&str> = encrypted.iter().map(|(_, v)| v.as_str()).collect();
+    let results = client.transit_decrypt(mount, key, &ciphertexts)?;
+    let mut decrypted = Vec::new();
+    for ((name, _), result) in encrypted.into_iter().zip(results) {
+        let plaintext = result
+            .and_then(|p| base64::decode(p).map_err(|e| e.to_string()))
+            .and_then(|p| String::from_utf8(p).map_err(|e| e.to_string()))
+            .map_err(|message| Error::DecryptError {
+                key: name.clone(),
+                message,
+            })?;
+        trace!("Decrypted {:?}", name);
+        decrypted.push((name.clone(), plaintext));
+    }
+    let path = format!("{}/decrypt/{}", mount, key);
+    for (name, plaintext) in decrypted {
+        env.decrypted(&name, plaintext, &path);
+    }
+    Ok(())
+}
+
+fn is_ciphertext(value: &str) -> bool {
+    let mut parts = value.splitn(3, ':');
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some("vault"), Some(version), Some(_)) => {
+            version.len() > 1
+                && version.starts_with('v')
+                && version[1..].chars().all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+fn fill_dependencies(env: &mut Merged, client: &consul::Client, app: &str) -> Result<(), Error> {
     let deps = match client.get::<Vec<String>>(&format!("config/{}/dependencies", app))? {
         Some(v) => v,
         None => return Ok(()),
@@ -213,20 +996,26 @@ fn fill_dependencies(
     trace!("Got dependencies: {:?}", deps);
     for dep in deps {
         let key = format!("{}_BASE_URL", dep.replace("-", "_").to_uppercase());
-        match client.get::<String>(&format!("infrastructure/service-uris/{}", key)) {
+        let path = format!("infrastructure/service-uris/{}", key);
+        match client.get::<String>(&path) {
             Ok(Some(val)) => {
                 trace!("Merging to environment: {:?}: {:?}", key, val);
-                env.insert(key, val);
+                env.insert(key, val, &Source::path(Layer::Dependencies, path, None));
             }
             Ok(None) => warn!("Missing URL for {}", dep),
             Err(e) => return Err(e.into()),
         };
 
         let frontend_key = format!("{}_FRONTEND_URL", dep.replace("-", "_").to_uppercase());
-        match client.get::<String>(&format!("infrastructure/service-uris/{}", frontend_key)) {
+        let path = format!("infrastructure/service-uris/{}", frontend_key);
+        match client.get::<String>(&path) {
             Ok(Some(val)) => {
                 trace!("Merging to environment: {:?}: {:?}", frontend_key, val);
-                env.insert(frontend_key, val);
+                env.insert(
+                    frontend_key,
+                    val,
+                    &Source::path(Layer::Dependencies, path, None),
+                );
             }
             Ok(None) => info!("Frontend URL for {} either not needed or not set", dep),
             Err(e) => return Err(e.into()),
@@ -235,27 +1024,82 @@ fn fill_dependencies(
     Ok(())
 }
 
-fn fill_generated(
-    env: &mut HashMap<String, String>,
-    client: &consul::Client,
-    app: &str,
-) -> Result<(), Error> {
-    if let Some(generated) =
-        client.get::<HashMap<String, String>>(&format!("config/{}/generated", app))?
-    {
+fn fill_generated(env: &mut Merged, client: &consul::Client, app: &str) -> Result<(), Error> {
+    let key = format!("config/{}/generated", app);
+    if let Some(generated) = client.get::<HashMap<String, String>>(&key)? {
         trace!("Merging to environment: {:?}", generated);
-        env.extend(generated);
+        env.extend(generated, &Source::path(Layer::Generated, key, None));
     }
     Ok(())
 }
 
+fn read_secret<P: AsRef<Path>>(path: P) -> Result<Secret, Error> {
+    let f = std::fs::File::open(path)?;
+    let mut reader = std::io::BufReader::new(f);
+    let mut string = String::new();
+    reader.read_to_string(&mut string)?;
+    Ok(string.trim().parse()?)
+}
+
+/// Whether `NO_RANCHER_METADATA` is set, where, unlike other flags, an empty
+/// value is true.
 fn skip_rancher_metadata_env() -> bool {
-    match std::env::var("NO_RANCHER_METADATA")
-        .ok()
-        .map(|s| s.to_lowercase())
-        .as_deref()
-    {
-        Some("false") | Some("0") | Some("no") | None => false,
-        Some(_) => true,
+    env_flag("NO_RANCHER_METADATA")
+        .unwrap_or_else(|| std::env::var_os("NO_RANCHER_METADATA").is_some())
+}
+
+/// Reads a flag from the environment, where any value other than `false`,
+/// `0`, or `no` is true, or `None` if the variable isn't set, or is empty.
+fn env_flag(name: &str) -> Option<bool> {
+    match std::env::var(name).ok()?.to_lowercase().as_str() {
+        "" => None,
+        "false" | "0" | "no" => Some(false),
+        _ => Some(true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ciphertexts() {
+        assert!(is_ciphertext("vault:v1:AbCd+/=="));
+        assert!(is_ciphertext("vault:v12:x"));
+        assert!(is_ciphertext("vault:v1:a:b"));
+    }
+
+    #[test]
+    fn not_ciphertexts() {
+        for value in &[
+            "",
+            "vault",
+            "vault:v1",
+            "vault:v:x",
+            "vault:1:x",
+            "vault:va:x",
+            "vault:v1a:x",
+            "Vault:v1:x",
+            "https://vault:8200/v1/x",
+        ] {
+            assert!(!is_ciphertext(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn flags() {
+        let name = "AVVOENV_TEST_FLAG";
+        for (value, flag) in &[
+            ("", None),
+            ("1", Some(true)),
+            ("true", Some(true)),
+            ("0", Some(false)),
+            ("No", Some(false)),
+        ] {
+            std::env::set_var(name, value);
+            assert_eq!(env_flag(name), *flag, "{:?}", value);
+        }
+        std::env::remove_var(name);
+        assert_eq!(env_flag(name), None);
     }
 }
@@ -0,0 +1,329 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fmt,
+    io::{self, Write},
+};
+
+use serde::Serialize;
+
+use crate::diff::Hasher;
+
+/// The sources merged into the environment, from lowest to highest
+/// precedence.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Layer {
+    Default,
+    Rancher,
+    GlobalConfig,
+    GlobalSecrets,
+    Dependencies,
+    Generated,
+    Config,
+    Secrets,
+    Dynamic,
+    Certificate,
+    Add,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Layer::Default => "default",
+            Layer::Rancher => "rancher",
+            Layer::GlobalConfig => "global-config",
+            Layer::GlobalSecrets => "global-secrets",
+            Layer::Dependencies => "dependencies",
+            Layer::Generated => "generated",
+            Layer::Config => "config",
+            Layer::Secrets => "secrets",
+            Layer::Dynamic => "dynamic",
+            Layer::Certificate => "certificate",
+            Layer::Add => "add",
+        })
+    }
+}
+
+impl Layer {
+    /// Whether values from the layer are config, written by people, rather
+    /// than secrets or values generated elsewhere.
+    pub fn is_config(self) -> bool {
+        matches!(
+            self,
+            Layer::Default
+                | Layer::GlobalConfig
+                | Layer::Dependencies
+                | Layer::Generated
+                | Layer::Config
+                | Layer::Add
+        )
+    }
+}
+
+/// Where a value came from.
+#[derive(Clone, Debug, Serialize)]
+pub struct Source {
+    pub layer: Layer,
+    /// The Consul key or Vault path read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub path: Option<String>,
+    /// The version of the config or secrets read.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<u64>,
+}
+
+impl Source {
+    pub fn new(layer: Layer) -> Source {
+        Source {
+            layer,
+            path: None,
+            version: None,
+        }
+    }
+
+    pub fn path<S: Into<String>>(layer: Layer, path: S, version: Option<u64>) -> Source {
+        Source {
+            layer,
+            path: Some(path.into()),
+            version,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+struct Value {
+    value: String,
+    #[serde(flatten)]
+    source: Source,
+    /// The Vault Transit path the value was decrypted with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    decrypted: Option<String>,
+    /// The variables referenced by the value, if it was interpolated.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    interpolated: Option<Vec<String>>,
+}
+
+/// The environment as it's merged from each source, keeping the values each
+/// variable shadowed, so that where a variable came from can be explained.
+#[derive(Debug, Default)]
+pub struct Merged(HashMap<String, Vec<Value>>);
+
+impl Merged {
+    pub fn insert(&mut self, key: String, value: String, source: &Source) {
+        self.0.entry(key).or_default().push(Value {
+            value,
+            source: source.clone(),
+            decrypted: None,
+            interpolated: None,
+        });
+    }
+
+    pub fn extend<I>(&mut self, iter: I, source: &Source)
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        for (key, value) in iter {
+            self.insert(key, value, source);
+        }
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.0.contains_key(key)
+    }
+
+    /// Keeps only the variables for which `f`, given the name and the layer
+    /// of the current value, returns true.
+    pub fn retain<F: FnMut(&str, Layer) -> bool>(&mut self, mut f: F) {
+        self.0.retain(|key, values| match values.last() {
+            Some(value) => f(key, value.source.layer),
+            None => false,
+        });
+    }
+
+    /// Whether the current value of `key` came from `layer`, or references a
+    /// variable that did, however indirectly.
+    pub fn comes_from(&self, key: &str, layer: Layer) -> bool {
+        let mut seen = HashSet::new();
+        let mut keys = vec![key];
+        while let Some(key) = keys.pop() {
+            if !seen.insert(key) {
+                continue;
+            }
+            let value = match self.0.get(key).and_then(|values| values.last()) {
+                Some(value) => value,
+                None => continue,
+            };
+            if value.source.layer == layer {
+                return true;
+            }
+            keys.extend(value.interpolated.iter().flatten().map(String::as_str));
+        }
+        false
+    }
+
+    /// The current value of each variable.
+    pub fn values(&self) -> impl Iterator<Item = (&String, &String)> {
+        self.0
+            .iter()
+            .filter_map(|(key, values)| Some((key, &values.last()?.value)))
+    }
+
+    /// The current value of each variable, split into the config values that
+    /// can be interpolated, and the rest, such as secrets and values that were
+    /// decrypted, which are used as they are.
+    pub fn templates(&self) -> (HashMap<String, String>, HashMap<String, String>) {
+        let mut templates = HashMap::new();
+        let mut literals = HashMap::new();
+        for (key, values) in &self.0 {
+            if let Some(value) = values.last() {
+                if value.source.layer.is_config() && value.decrypted.is_none() {
+                    templates.insert(key.clone(), value.value.clone());
+                } else {
+                    literals.insert(key.clone(), value.value.clone());
+                }
+            }
+        }
+        (templates, literals)
+    }
+
+    /// Replaces the current value of `key` with the `plaintext` decrypted
+    /// from it with the Vault Transit `path`.
+    pub fn decrypted(&mut self, key: &str, plaintext: String, path: &str) {
+        if let Some(value) = self.0.get_mut(key).and_then(|values| values.last_mut()) {
+            value.value = plaintext;
+            value.decrypted = Some(path.to_owned());
+        }
+    }
+
+    /// Replaces the current value of `key` with the `value` interpolated from
+    /// it, referencing the variables in `references`.
+    pub fn interpolated(&mut self, key: &str, value: String, references: Vec<String>) {
+        if let Some(current) = self.0.get_mut(key).and_then(|values| values.last_mut()) {
+            current.value = value;
+            current.interpolated = Some(references);
+        }
+    }
+
+    pub fn into_map(self) -> HashMap<String, String> {
+        self.0
+            .into_iter()
+            .filter_map(|(key, mut values)| Some((key, values.pop()?.value)))
+            .collect()
+    }
+
+    /// Writes a table of where each variable in `names`, or all of them if
+    /// empty, came from, followed by the values they shadowed. Values are
+    /// written as a prefix of their keyed hash, unless `reveal` is set.
+    pub fn to_writer<W: Write>(
+        &self,
+        mut writer: W,
+        names: &[String],
+        reveal: bool,
+    ) -> io::Result<()> {
+        let mut rows = vec![[
+            "NAME".to_owned(),
+            "SOURCE".to_owned(),
+            "PATH".to_owned(),
+            "VERSION".to_owned(),
+            "VALUE".to_owned(),
+        ]];
+        let hasher = Hasher::random();
+        for (key, values) in self.selected(names) {
+            for (i, value) in values.iter().rev().enumerate() {
+                let mut shown = show(&value.value, reveal, &hasher);
+                if let Some(ref path) = value.decrypted {
+                    shown = format!("{} (decrypted with {})", shown, path);
+                }
+                if let Some(ref references) = value.interpolated {
+                    shown = format!("{} (interpolated from {})", shown, references.join(", "));
+                }
+                if i > 0 {
+                    shown = format!("{} (shadowed)", shown);
+                }
+                rows.push([
+                    if i == 0 { key.clone() } else { String::new() },
+                    value.source.layer.to_string(),
+                    value.source.path.clone().unwrap_or_default(),
+                    value
+                        .source
+                        .version
+                        .map(|v| v.to_string())
+                        .unwrap_or_default(),
+                    shown,
+                ]);
+            }
+        }
+        let mut widths = [0; 4];
+        for row in &rows {
+            for (width, cell) in widths.iter_mut().zip(row.iter()) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+        for row in rows {
+            writeln!(
+                writer,
+                "{:5$}  {:6$}  {:7$}  {:8$}  {}",
+                row[0], row[1], row[2], row[3], row[4], widths[0], widths[1], widths[2], widths[3]
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Writes where each variable in `names`, or all of them if empty, came
+    /// from as a JSON object, with the values they shadowed under `shadowed`.
+    pub fn to_json_writer<W: Write>(
+        &self,
+        writer: W,
+        names: &[String],
+        reveal: bool,
+    ) -> serde_json::Result<()> {
+        #[derive(Serialize)]
+        struct Explained {
+            #[serde(flatten)]
+            value: Value,
+            shadowed: Vec<Value>,
+        }
+
+        let hasher = Hasher::random();
+        let mask = |value: &Value| Value {
+            value: if reveal {
+                value.value.clone()
+            } else {
+                hasher.hash(&value.value)
+            },
+            ..value.clone()
+        };
+        let explained: BTreeMap<_, _> = self
+            .selected(names)
+            .filter_map(|(key, values)| {
+                let (last, rest) = values.split_last()?;
+                Some((
+                    key,
+                    Explained {
+                        value: mask(last),
+                        shadowed: rest.iter().rev().map(mask).collect(),
+                    },
+                ))
+            })
+            .collect();
+        serde_json::to_writer_pretty(writer, &explained)
+    }
+
+    fn selected<'a>(
+        &'a self,
+        names: &'a [String],
+    ) -> impl Iterator<Item = (&'a String, &'a Vec<Value>)> + 'a {
+        let sorted: BTreeMap<_, _> = self.0.iter().collect();
+        sorted
+            .into_iter()
+            .filter(move |(key, _)| names.is_empty() || names.contains(key))
+    }
+}
+
+fn show(value: &str, reveal: bool, hasher: &Hasher) -> String {
+    if reveal {
+        format!("{:?}", value)
+    } else {
+        hasher.hash(value)
+    }
+}
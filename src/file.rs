@@ -0,0 +1,24 @@
+use std::{
+    fs::{remove_file, rename, OpenOptions},
+    io::{self, Write},
+    os::unix::fs::OpenOptionsExt,
+    path::Path,
+};
+
+/// Writes `contents` to `path` readable only by the current user, replacing
+/// any existing file atomically.
+pub fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    let mut tmp = path.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    match remove_file(&tmp) {
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => (),
+        result => result?,
+    }
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&tmp)?;
+    file.write_all(contents)?;
+    rename(tmp, path)
+}
@@ -0,0 +1,296 @@
+use std::{
+    cmp::min,
+    collections::hash_map::RandomState,
+    fs::{read, read_dir},
+    hash::{BuildHasher, Hasher},
+    net::{SocketAddr, ToSocketAddrs},
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::Duration,
+};
+
+use log::{debug, warn};
+use reqwest::{
+    blocking::{ClientBuilder, RequestBuilder, Response},
+    header::RETRY_AFTER,
+    tls::{Certificate, Identity},
+    Method, StatusCode, Url,
+};
+
+use crate::client_error::ClientError;
+
+/// TLS settings for the connection to Consul or Vault.
+#[derive(Debug, Default)]
+pub struct Tls {
+    /// A PEM file of CA certificates to trust, in addition to the system's.
+    pub ca_cert: Option<PathBuf>,
+    /// A directory of PEM files of CA certificates to trust.
+    pub ca_path: Option<PathBuf>,
+    /// A PEM client certificate, presented with `client_key`.
+    pub client_cert: Option<PathBuf>,
+    /// The PKCS #8 PEM private key for `client_cert`.
+    pub client_key: Option<PathBuf>,
+    /// Don't verify the server's certificate.
+    pub skip_verify: bool,
+    /// The name to verify the server's certificate against, and send with
+    /// SNI, instead of the host in the address.
+    pub server_name: Option<String>,
+}
+
+/// How requests are timed out, and retried after transient failures.
+#[derive(Clone, Debug)]
+pub struct Retry {
+    pub connect_timeout: Duration,
+    pub timeout: Duration,
+    /// The most times a request is sent, including the first.
+    pub max_attempts: u32,
+    /// The longest to wait between attempts.
+    pub max_backoff: Duration,
+}
+
+impl Retry {
+    /// The wait before the attempt after `attempt`, doubling from a second
+    /// up to `max_backoff`, with the later half of each wait randomised so
+    /// that many clients failing together don't all retry together.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        let backoff = min(
+            Duration::from_secs(1 << min(attempt - 1, 16)),
+            self.max_backoff,
+        );
+        let random = RandomState::new().build_hasher().finish();
+        backoff / 2 + backoff.mul_f64((random % 1000) as f64 / 2000.0)
+    }
+}
+
+/// An HTTP client that retries requests that fail to connect, or get
+/// `429 Too Many Requests`. Requests that only read are also retried after
+/// timeouts and server errors, but others aren't, as the server may have
+/// acted on them, and they may not be safe to repeat, such as unwrapping a
+/// token or a check-and-set write.
+#[derive(Clone, Debug)]
+pub struct Client {
+    http: reqwest::blocking::Client,
+    retry: Retry,
+}
+
+impl Client {
+    pub fn get(&self, url: Url) -> RequestBuilder {
+        self.http.get(url)
+    }
+
+    pub fn post(&self, url: Url) -> RequestBuilder {
+        self.http.post(url)
+    }
+
+    pub fn put(&self, url: Url) -> RequestBuilder {
+        self.http.put(url)
+    }
+
+    /// The wait before the attempt after `attempt`, as for retried requests.
+    pub fn backoff(&self, attempt: u32) -> Duration {
+        self.retry.backoff(attempt)
+    }
+
+    /// Sends `request`, retrying according to the retry policy. Once out of
+    /// attempts the last response is returned, whatever its status.
+    pub fn send(&self, request: RequestBuilder) -> reqwest::Result<Response> {
+        let request = request.build()?;
+        let repeatable = is_safe(request.method());
+        let mut attempt = 1;
+        loop {
+            let this = match request.try_clone() {
+                Some(this) if attempt < self.retry.max_attempts => this,
+                _ => return self.http.execute(request),
+            };
+            let wait = match self.http.execute(this) {
+                Ok(response) if is_transient(response.status(), repeatable) => {
+                    debug!(
+                        "{} {}: {}",
+                        request.method(),
+                        request.url(),
+                        response.status()
+                    );
+                    retry_after(&response)
+                }
+                Err(e) if e.is_connect() || (repeatable && (e.is_timeout() || e.is_request())) => {
+                    debug!("{} {}: {}", request.method(), request.url(), e);
+                    None
+                }
+                result => return result,
+            };
+            let backoff = min(
+                wait.unwrap_or_default().max(self.retry.backoff(attempt)),
+                self.retry.max_backoff,
+            );
+            debug!("Retrying {} in {:?}", request.url(), backoff);
+            sleep(backoff);
+            attempt += 1;
+        }
+    }
+}
+
+/// Whether a request with `method` only reads, and so can be repeated
+/// whether or not the server acted on it.
+fn is_safe(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+fn is_transient(status: StatusCode, repeatable: bool) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || (repeatable && status.is_server_error())
+}
+
+/// The wait requested by the server with a `Retry-After` header in seconds.
+fn retry_after(response: &Response) -> Option<Duration> {
+    response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+        .map(Duration::from_secs)
+}
+
+/// Builds an HTTP client for the server at `address`. If the TLS settings
+/// include a server name, the host in `address` is replaced with it, and the
+/// client resolves that name to the addresses of the original host.
+pub fn client(address: &mut Url, tls: &Tls, retry: &Retry) -> Result<Client, ClientError> {
+    let mut builder = ClientBuilder::new()
+        .connect_timeout(retry.connect_timeout)
+        .timeout(retry.timeout);
+    if let Some(ref path) = tls.ca_cert {
+        debug!("Trusting CA certificates from {:?}", path);
+        let certs = Certificate::from_pem_bundle(&read_file(path)?)
+            .map_err(|e| ClientError::TlsError(path.clone(), e))?;
+        if certs.is_empty() {
+            warn!("No certificates found in {:?}", path);
+        }
+        for cert in certs {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    if let Some(ref path) = tls.ca_path {
+        debug!("Trusting CA certificates in {:?}", path);
+        let entries = read_dir(path).map_err(|e| ClientError::IoError(path.clone(), e))?;
+        for entry in entries {
+            let path = entry
+                .map_err(|e| ClientError::IoError(path.clone(), e))?
+                .path();
+            if !path.is_file() {
+                continue;
+            }
+            match Certificate::from_pem_bundle(&read_file(&path)?) {
+                Ok(certs) => {
+                    for cert in certs {
+                        builder = builder.add_root_certificate(cert);
+                    }
+                }
+                Err(e) => warn!("Skipping {:?}: {}", path, e),
+            }
+        }
+    }
+    if let (Some(cert_path), Some(key_path)) = (&tls.client_cert, &tls.client_key) {
+        debug!("Using client certificate {:?}", cert_path);
+        let identity = Identity::from_pkcs8_pem(&read_file(cert_path)?, &read_file(key_path)?)
+            .map_err(|e| ClientError::TlsError(key_path.clone(), e))?;
+        builder = builder.identity(identity);
+    }
+    if tls.skip_verify {
+        warn!("Not verifying the TLS certificate of {}", address);
+        builder = builder.danger_accept_invalid_certs(true);
+    }
+    if let Some(ref name) = tls.server_name {
+        let addrs = resolve(address)?;
+        debug!("Connecting to {} as {:?} at {:?}", address, name, addrs);
+        address.set_host(Some(name))?;
+        builder = builder.resolve_to_addrs(name, &addrs);
+    }
+    let http = builder
+        .build()
+        .map_err(|e| ClientError::with_url(address.clone(), e))?;
+    Ok(Client {
+        http,
+        retry: retry.clone(),
+    })
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, ClientError> {
+    read(path).map_err(|e| ClientError::IoError(path.to_owned(), e))
+}
+
+fn resolve(address: &Url) -> Result<Vec<SocketAddr>, ClientError> {
+    let port = address.port_or_known_default().unwrap_or(443);
+    match address.host() {
+        Some(url::Host::Domain(domain)) => (domain, port)
+            .to_socket_addrs()
+            .map(Iterator::collect)
+            .map_err(|e| ClientError::ResolveError(address.clone(), e)),
+        Some(url::Host::Ipv4(ip)) => Ok(vec![(ip, port).into()]),
+        Some(url::Host::Ipv6(ip)) => Ok(vec![(ip, port).into()]),
+        None => Err(ClientError::BaseUrlError(address.clone())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{fixture, retry, Stub};
+    use serde_json::json;
+
+    fn get(mut address: Url, tls: &Tls) -> Result<StatusCode, ClientError> {
+        let client = client(&mut address, tls, &retry())?;
+        let response = client
+            .send(client.get(address.clone()))
+            .map_err(|e| ClientError::with_url(address, e))?;
+        Ok(response.status())
+    }
+
+    fn stub() -> Stub {
+        Stub::tls(|_| (200, json!({})))
+    }
+
+    #[test]
+    fn rejects_untrusted_certificates() {
+        let tls = Tls {
+            server_name: Some("vault.test".to_owned()),
+            ..Tls::default()
+        };
+        assert!(get(stub().address, &tls).is_err());
+    }
+
+    #[test]
+    fn trusts_ca_cert() {
+        let tls = Tls {
+            ca_cert: Some(fixture("vault.test.crt")),
+            server_name: Some("vault.test".to_owned()),
+            ..Tls::default()
+        };
+        let stub = stub();
+        assert_eq!(get(stub.address.clone(), &tls).unwrap(), StatusCode::OK);
+        let request = &stub.requests()[0];
+        assert_eq!(
+            (request.method.as_str(), request.path.as_str()),
+            ("GET", "/")
+        );
+        let host = format!("vault.test:{}", stub.address.port().unwrap());
+        assert_eq!(request.header("host"), Some(host.as_str()));
+    }
+
+    #[test]
+    fn verifies_server_name() {
+        let tls = Tls {
+            ca_cert: Some(fixture("vault.test.crt")),
+            ..Tls::default()
+        };
+        assert!(get(stub().address, &tls).is_err());
+    }
+
+    #[test]
+    fn skips_verification() {
+        let tls = Tls {
+            skip_verify: true,
+            ..Tls::default()
+        };
+        assert_eq!(get(stub().address, &tls).unwrap(), StatusCode::OK);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{collections::HashMap, fmt};
+
+use log::{trace, warn};
+
+#[derive(Debug)]
+pub enum Error {
+    CycleError(Vec<String>),
+    SyntaxError { key: String, message: String },
+    UndefinedError { key: String, reference: String },
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::CycleError(keys) => write!(f, "reference cycle: {}", keys.join(" -> ")),
+            Error::SyntaxError { key, message } => {
+                write!(f, "invalid reference in {}: {}", key, message)
+            }
+            Error::UndefinedError { key, reference } => {
+                write!(f, "{} references {}, which is not set", key, reference)
+            }
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// A value with its references replaced.
+#[derive(Debug)]
+pub struct Interpolated {
+    pub value: String,
+    /// The variables referenced, in the order they appear.
+    pub references: Vec<String>,
+}
+
+/// Replaces references to other variables in the values of `env`, returning
+/// the values that changed. References can be to variables in `env` or in
+/// `literals`, whose values are used as they are, rather than being read as
+/// templates themselves.
+///
+/// `${NAME}` is replaced with the value of `NAME`, and `${NAME:-default}`
+/// with `default` if `NAME` is unset or empty, where `default` can contain
+/// references itself. `$$` is replaced with a literal `$`, and any other `$`
+/// is left as is. References to variables that aren't set are an error if
+/// `strict` is set, otherwise they're replaced with nothing.
+pub fn interpolate(
+    env: &HashMap<String, String>,
+    literals: &HashMap<String, String>,
+    strict: bool,
+) -> Result<Vec<(String, Interpolated)>, Error> {
+    let mut interpolator = Interpolator {
+        env,
+        literals,
+        strict,
+        done: HashMap::new(),
+        stack: Vec::new(),
+    };
+    for (key, value) in env {
+        if value.contains('$') {
+            interpolator.resolve(key)?;
+        }
+    }
+    let mut done = interpolator.done;
+    Ok(env
+        .iter()
+        .filter_map(|(key, value)| {
+            let interpolated = done.remove(key)?;
+            if interpolated.value == *value {
+                return None;
+            }
+            trace!("Interpolated {:?}", key);
+            Some((key.clone(), interpolated))
+        })
+        .collect())
+}
+
+struct Interpolator<'a> {
+    env: &'a HashMap<String, String>,
+    literals: &'a HashMap<String, String>,
+    strict: bool,
+    done: HashMap<String, Interpolated>,
+    /// The keys being resolved, to detect cycles.
+    stack: Vec<String>,
+}
+
+impl<'a> Interpolator<'a> {
+    fn resolve(&mut self, key: &str) -> Result<&Interpolated, Error> {
+        if !self.done.contains_key(key) {
+            if let Some(start) = self.stack.iter().position(|k| k == key) {
+                let mut cycle = self.stack[start..].to_vec();
+                cycle.push(key.to_owned());
+                return Err(Error::CycleError(cycle));
+            }
+            self.stack.push(key.to_owned());
+            let env = self.env;
+            let mut references = Vec::new();
+            let value = self.expand(key, &env[key], &mut references)?;
+            self.stack.pop();
+            self.done
+                .insert(key.to_owned(), Interpolated { value, references });
+        }
+        Ok(&self.done[key])
+    }
+
+    fn expand(
+        &mut self,
+        key: &str,
+        template: &str,
+        references: &mut Vec<String>,
+    ) -> Result<String, Error> {
+        let syntax_error = |message: &str| Error::SyntaxError {
+            key: key.to_owned(),
+            message: message.to_owned(),
+        };
+        let mut out = String::with_capacity(template.len());
+        let mut rest = template;
+        while let Some(i) = rest.find('$') {
+            out.push_str(&rest[..i]);
+            rest = &rest[i..];
+            if rest.starts_with("$$") {
+                out.push('$');
+                rest = &rest[2..];
+                continue;
+            }
+            if !rest.starts_with("${") {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            }
+            let end = closing_brace(rest).ok_or_else(|| syntax_error("unterminated ${"))?;
+            let inner = &rest[2..end];
+            rest = &rest[end + 1..];
+            let (name, default) = match inner.find(":-") {
+                Some(i) => (&inner[..i], Some(&inner[i + 2..])),
+                None => (inner, None),
+            };
+            if !is_name(name) {
+                return Err(syntax_error(&format!("{:?} is not a variable name", name)));
+            }
+            references.push(name.to_owned());
+            let value = if self.env.contains_key(name) {
+                Some(self.resolve(name)?.value.clone())
+            } else {
+                self.literals.get(name).cloned()
+            };
+            match (value, default) {
+                (Some(value), Some(default)) if value.is_empty() => {
+                    out.push_str(&self.expand(key, default, references)?)
+                }
+                (Some(value), _) => out.push_str(&value),
+                (None, Some(default)) => out.push_str(&self.expand(key, default, references)?),
+                (None, None) if self.strict => {
+                    return Err(Error::UndefinedError {
+                        key: key.to_owned(),
+                        reference: name.to_owned(),
+                    })
+                }
+                (None, None) => warn!("{} references {}, which is not set", key, name),
+            }
+        }
+        out.push_str(rest);
+        Ok(out)
+    }
+}
+
+/// The index of the `}` closing the `${` at the start of `s`, skipping over
+/// any references nested in a default.
+fn closing_brace(s: &str) -> Option<usize> {
+    let mut depth = 0;
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '$' => match chars.peek() {
+                Some(&(_, '{')) => {
+                    chars.next();
+                    depth += 1;
+                }
+                Some(&(_, '$')) => {
+                    chars.next();
+                }
+                _ => (),
+            },
+            '}' => {
+                depth -= 1;
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+            _ => (),
+        }
+    }
+    None
+}
+
+fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    match chars.next() {
+        Some(c) if c == '_' || c.is_ascii_alphabetic() => {
+            chars.all(|c| c == '_' || c.is_ascii_alphanumeric())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn env(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    /// The interpolated value of `key`, or its original value if unchanged.
+    fn value(pairs: &[(&str, &str)], key: &str, strict: bool) -> Result<String, Error> {
+        let env = env(pairs);
+        let changed = interpolate(&env, &HashMap::new(), strict)?;
+        Ok(changed
+            .into_iter()
+            .find(|(k, _)| k == key)
+            .map_or_else(|| env[key].clone(), |(_, i)| i.value))
+    }
+
+    #[test]
+    fn replaces_references() {
+        let pairs = [("HOST", "db"), ("URL", "pg://${HOST}/app")];
+        assert_eq!(value(&pairs, "URL", false).unwrap(), "pg://db/app");
+    }
+
+    #[test]
+    fn records_references() {
+        let env = env(&[("A", "a"), ("B", "b"), ("C", "${B}${A}")]);
+        let changed = interpolate(&env, &HashMap::new(), false).unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].1.references, ["B", "A"]);
+    }
+
+    #[test]
+    fn literals_are_not_templates() {
+        let env = env(&[("URL", "pg://${USER}:${PASSWORD}@db")]);
+        let literals = [("USER", "app"), ("PASSWORD", "p$$w${rd")]
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        let changed = interpolate(&env, &literals, true).unwrap();
+        assert_eq!(changed[0].1.value, "pg://app:p$$w${rd@db");
+    }
+
+    #[test]
+    fn nested_defaults() {
+        let pairs = [
+            ("PORT", "5432"),
+            ("URL", "${HOST:-${FALLBACK:-db}:${PORT}}"),
+        ];
+        assert_eq!(value(&pairs, "URL", true).unwrap(), "db:5432");
+        let pairs = [("HOST", ""), ("URL", "${HOST:-local}")];
+        assert_eq!(value(&pairs, "URL", true).unwrap(), "local");
+    }
+
+    #[test]
+    fn escapes_dollars() {
+        let pairs = [("A", "a"), ("B", "$${A} $$ $A ${A}")];
+        assert_eq!(value(&pairs, "B", false).unwrap(), "${A} $ $A a");
+    }
+
+    #[test]
+    fn detects_cycles() {
+        let pairs = [("A", "${B}"), ("B", "${C}"), ("C", "${A}")];
+        match value(&pairs, "A", false) {
+            Err(Error::CycleError(keys)) => assert_eq!(keys.len(), 4),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn strict_errors_on_unset() {
+        let pairs = [("A", "x${UNSET}y")];
+        assert_eq!(value(&pairs, "A", false).unwrap(), "xy");
+        assert!(matches!(
+            value(&pairs, "A", true),
+            Err(Error::UndefinedError { .. })
+        ));
+    }
+
+    #[test]
+    fn syntax_errors() {
+        assert!(matches!(
+            value(&[("A", "${B")], "A", false),
+            Err(Error::SyntaxError { .. })
+        ));
+        assert!(matches!(
+            value(&[("A", "${1B}")], "A", false),
+            Err(Error::SyntaxError { .. })
+        ));
+    }
+}
@@ -1,18 +1,35 @@
+mod cache;
 mod client_error;
+mod config;
 mod consul;
+mod diff;
+mod dynamic;
 mod env;
+mod explain;
+mod file;
 mod format;
+mod http;
+mod interpolate;
+mod pki;
 mod prompt;
 mod rancher_metadata;
+mod schema;
+mod secret;
 mod service;
+#[cfg(test)]
+mod stub;
+mod supervisor;
 mod vault;
 
 use std::{
     cmp::max,
+    collections::HashMap,
     fs::File,
-    io,
+    io::{self, Write},
     os::unix::process::CommandExt,
     path::{Path, PathBuf},
+    process::Command,
+    time::Duration,
 };
 
 use glob::Pattern;
@@ -20,16 +37,19 @@ use log::{debug, error, info, trace, warn};
 use reqwest::Url;
 use structopt::{
     clap::AppSettings::{
-        ArgRequiredElseHelp, ArgsNegateSubcommands, DisableHelpSubcommand, TrailingVarArg,
-        VersionlessSubcommands,
+        AllArgsOverrideSelf, ArgRequiredElseHelp, ArgsNegateSubcommands, DisableHelpSubcommand,
+        TrailingVarArg, VersionlessSubcommands,
     },
+    clap::{self, ArgGroup, ArgMatches},
     StructOpt,
 };
 
 use format::Format;
+use secret::Secret;
 
 fn main() {
-    let opts = Opts::from_args();
+    let matches = Opts::clap().get_matches();
+    let mut opts = Opts::from_clap(&matches);
 
     let verbosity = std::env::var("AVVOENV_LOG_LEVEL")
         .map(verbosity)
@@ -41,12 +61,25 @@ fn main() {
         .init()
         .unwrap();
 
+    if let (Some(subcommand), (_, Some(matches))) = (&mut opts.subcommand, matches.subcommand()) {
+        match configure(subcommand, matches) {
+            Ok(paths) => debug!("Read config from {:?}", paths),
+            Err(e) => {
+                error!("{}", e);
+                std::process::exit(1);
+            }
+        }
+    }
     debug!("{:#?}", opts);
 
     let result = match opts.subcommand {
         Some(Subcommand::Exec(opts)) => exec(opts),
         Some(Subcommand::Write(opts)) => write(opts),
         Some(Subcommand::Service(opts)) => service(opts),
+        Some(Subcommand::Diff(opts)) => diff(*opts),
+        Some(Subcommand::Explain(opts)) => explain(opts),
+        Some(Subcommand::Get(opts)) => get(opts),
+        Some(Subcommand::Set(opts)) => set(opts),
         None => plugin(opts.script.unwrap(), opts.args),
     };
 
@@ -70,7 +103,7 @@ fn verbosity(s: String) -> usize {
 
 #[derive(StructOpt, Debug)]
 #[structopt(
-    settings = &[ArgsNegateSubcommands, ArgRequiredElseHelp, DisableHelpSubcommand, VersionlessSubcommands]
+    settings = &[ArgsNegateSubcommands, ArgRequiredElseHelp, DisableHelpSubcommand, VersionlessSubcommands], global_settings = &[AllArgsOverrideSelf]
 )]
 struct Opts {
     /// Verbose mode, multiples increase the verbosity
@@ -87,6 +120,70 @@ struct Opts {
     args: Vec<String>,
 }
 
+/// Sets the options `subcommand` wasn't given from the config files, returning
+/// the paths of the files read.
+fn configure(
+    subcommand: &mut Subcommand,
+    matches: &ArgMatches,
+) -> Result<Vec<PathBuf>, config::Error> {
+    let given = config::Given::new(matches);
+    let opts = match subcommand {
+        Subcommand::Service(opts) => {
+            let config = config::Config::load(opts.profile.as_deref())?;
+            config.fill_service(&mut opts.service, &given);
+            return Ok(config.paths);
+        }
+        Subcommand::Exec(opts) => &mut opts.fetch,
+        Subcommand::Write(opts) => &mut opts.fetch,
+        Subcommand::Diff(opts) => &mut opts.fetch,
+        Subcommand::Explain(opts) => &mut opts.fetch,
+        Subcommand::Get(opts) => &mut opts.fetch,
+        Subcommand::Set(opts) => &mut opts.fetch,
+    };
+    let config = config::Config::load(opts.profile.as_deref())?;
+    config.fill(opts, &given);
+    // as with consul(1), a token on the command line takes precedence over a
+    // token file, then a token from the environment over a token file
+    if matches.occurrences_of("consul-token") > 0
+        || (matches.occurrences_of("consul-token-file") == 0 && opts.consul_token.is_some())
+    {
+        opts.consul_token_file = None;
+    }
+    require(opts);
+    Ok(config.paths)
+}
+
+/// Exits with a usage error if the options required to fetch the environment
+/// weren't set on the command line, with environment variables, or by the
+/// config files. These can't be left to clap, as it doesn't know about the
+/// config files.
+fn require(opts: &FetchOpts) {
+    let mut missing = Vec::new();
+    if opts.consul.is_none() {
+        missing.push("--consul <URL>");
+    }
+    if opts.vault.is_none() {
+        missing.push("--vault <URL>");
+    }
+    if !opts.dev
+        && opts.token.is_none()
+        && opts.wrapped_token.is_none()
+        && opts.app_user.is_none()
+        && opts.app_id.is_none()
+        && opts.role_id.is_none()
+        && opts.kubernetes_role.is_none()
+    {
+        missing.push("--vault-token <TOKEN>");
+    }
+    if !missing.is_empty() {
+        let message = format!(
+            "The following required arguments were not provided:\n    {}\n\nFor more information try --help",
+            missing.join("\n    ")
+        );
+        clap::Error::with_description(&message, clap::ErrorKind::MissingRequiredArgument).exit();
+    }
+}
+
 #[derive(StructOpt, Debug)]
 enum Subcommand {
     /// Execute the given command with the fetched environment variables
@@ -102,10 +199,25 @@ enum Subcommand {
     /// Print the canonical name of the current service
     #[structopt(name = "service", no_version)]
     Service(ServiceOpts),
+    /// Compare the fetched environment variables with another environment
+    #[structopt(name = "diff", no_version)]
+    Diff(Box<DiffOpts>),
+    /// Show where each of the fetched environment variables came from
+    #[structopt(name = "explain", no_version)]
+    Explain(ExplainOpts),
+    /// Print the values of the given fetched environment variables
+    #[structopt(name = "get", no_version)]
+    Get(GetOpts),
+    /// Publish a new version of the service's config and secrets
+    #[structopt(name = "set", alias = "put", no_version)]
+    Set(SetOpts),
 }
 
-#[derive(StructOpt, Debug)]
+#[derive(StructOpt, Clone, Debug)]
 pub(crate) struct FetchOpts {
+    /// use the options from a profile in the config files
+    #[structopt(long = "profile", value_name = "NAME", env = "AVVOENV_PROFILE")]
+    profile: Option<String>,
     /// set the service name
     #[structopt(short = "s", long = "service", value_name = "NAME", env = "SERVICE")]
     service: Option<String>,
@@ -116,10 +228,115 @@ pub(crate) struct FetchOpts {
         value_name = "URL",
         env = "CONSUL_HTTP_ADDR"
     )]
-    consul: Url,
+    consul: Option<Url>,
+    /// set the consul ACL token
+    #[structopt(long = "consul-token", value_name = "TOKEN", env = "CONSUL_HTTP_TOKEN")]
+    consul_token: Option<Secret>,
+    /// read the consul ACL token from a file
+    #[structopt(
+        long = "consul-token-file",
+        value_name = "FILE",
+        env = "CONSUL_HTTP_TOKEN_FILE"
+    )]
+    consul_token_file: Option<PathBuf>,
+    /// set the consul datacenters, falling back to each in order
+    #[structopt(
+        long = "consul-datacenter",
+        value_name = "DC",
+        env = "CONSUL_DATACENTER",
+        use_delimiter = true
+    )]
+    consul_datacenters: Vec<String>,
+    /// set the consul namespace
+    #[structopt(
+        long = "consul-namespace",
+        value_name = "NAMESPACE",
+        env = "CONSUL_NAMESPACE"
+    )]
+    consul_namespace: Option<String>,
+    /// trust the CA certificates in a PEM file when connecting to consul
+    #[structopt(long = "consul-ca-cert", value_name = "FILE", env = "CONSUL_CACERT")]
+    consul_ca_cert: Option<PathBuf>,
+    /// trust the CA certificates in a directory when connecting to consul
+    #[structopt(long = "consul-ca-path", value_name = "DIR", env = "CONSUL_CAPATH")]
+    consul_ca_path: Option<PathBuf>,
+    /// present a client certificate to consul
+    #[structopt(
+        long = "consul-client-cert",
+        value_name = "FILE",
+        env = "CONSUL_CLIENT_CERT",
+        requires = "consul-client-key"
+    )]
+    consul_client_cert: Option<PathBuf>,
+    /// set the PKCS #8 private key for --consul-client-cert
+    #[structopt(
+        long = "consul-client-key",
+        value_name = "FILE",
+        env = "CONSUL_CLIENT_KEY",
+        requires = "consul-client-cert"
+    )]
+    consul_client_key: Option<PathBuf>,
+    /// don't verify the consul TLS certificate
+    #[structopt(long = "consul-skip-verify")]
+    consul_skip_verify: bool,
+    /// set the name to verify the consul TLS certificate against
+    #[structopt(
+        long = "consul-tls-server-name",
+        value_name = "NAME",
+        env = "CONSUL_TLS_SERVER_NAME"
+    )]
+    consul_tls_server_name: Option<String>,
     /// set the vault host
     #[structopt(short = "u", long = "vault", value_name = "URL", env = "VAULT_ADDR")]
-    vault: Url,
+    vault: Option<Url>,
+    /// set the vault enterprise namespace
+    #[structopt(
+        long = "vault-namespace",
+        value_name = "NAMESPACE",
+        env = "VAULT_NAMESPACE"
+    )]
+    vault_namespace: Option<String>,
+    /// set the vault namespace for a path, overriding --vault-namespace
+    #[structopt(
+        long = "vault-path-namespace",
+        value_name = "PATH=NAMESPACE",
+        env = "VAULT_PATH_NAMESPACE",
+        use_delimiter = true,
+        parse(from_str = parse_add)
+    )]
+    vault_path_namespaces: Vec<(String, String)>,
+    /// trust the CA certificates in a PEM file when connecting to vault
+    #[structopt(long = "vault-ca-cert", value_name = "FILE", env = "VAULT_CACERT")]
+    vault_ca_cert: Option<PathBuf>,
+    /// trust the CA certificates in a directory when connecting to vault
+    #[structopt(long = "vault-ca-path", value_name = "DIR", env = "VAULT_CAPATH")]
+    vault_ca_path: Option<PathBuf>,
+    /// present a client certificate to vault
+    #[structopt(
+        long = "vault-client-cert",
+        value_name = "FILE",
+        env = "VAULT_CLIENT_CERT",
+        requires = "vault-client-key"
+    )]
+    vault_client_cert: Option<PathBuf>,
+    /// set the PKCS #8 private key for --vault-client-cert
+    #[structopt(
+        long = "vault-client-key",
+        value_name = "FILE",
+        env = "VAULT_CLIENT_KEY",
+        requires = "vault-client-cert"
+    )]
+    vault_client_key: Option<PathBuf>,
+    /// don't verify the vault TLS certificate
+    #[structopt(long = "vault-skip-verify")]
+    vault_skip_verify: bool,
+    /// set the name to verify the vault TLS certificate against
+    #[structopt(
+        long = "vault-tls-server-name",
+        value_name = "NAME",
+        env = "VAULT_TLS_SERVER_NAME"
+    )]
+    vault_tls_server_name: Option<String>,
     /// authenticate with vault
     #[structopt(long = "dev")]
     dev: bool,
@@ -142,33 +359,212 @@ pub(crate) struct FetchOpts {
         short = "t",
         long = "vault-token",
         value_name = "TOKEN",
-        env = "VAULT_TOKEN",
-        required_unless_one = &["dev", "app_user", "app_id"]
+        env = "VAULT_TOKEN"
+    )]
+    token: Option<Secret>,
+    /// unwrap a vault token from a response-wrapping token
+    #[structopt(
+        long = "vault-wrapped-token",
+        value_name = "TOKEN",
+        conflicts_with = "dev",
+        env = "VAULT_WRAPPED_TOKEN"
     )]
-    token: Option<vault::Secret>,
+    wrapped_token: Option<Secret>,
+    /// set the path the wrapped token must have been created at
+    #[structopt(
+        long = "vault-wrapped-path",
+        value_name = "PATTERN",
+        default_value = "auth/token/create*",
+        env = "VAULT_WRAPPED_PATH"
+    )]
+    wrapped_path: Pattern,
     /// authenticate with vault app-user
     #[structopt(
         short = "r",
         long = "app-user",
         value_name = "VAULT_APP_USER",
-        requires = "app_id",
+        requires = "app-id",
         conflicts_with = "dev",
         env = "VAULT_APP_USER"
     )]
-    app_user: Option<vault::Secret>,
+    app_user: Option<Secret>,
     /// authenticate with vault app-id
     #[structopt(
         short = "p",
         long = "app-id",
         value_name = "VAULT_APP_ID",
-        requires = "app_user",
+        requires = "app-user",
         conflicts_with = "dev",
         env = "VAULT_APP_ID"
     )]
     app_id: Option<String>,
+    /// set the version of the service's vault secrets
+    #[structopt(
+        long = "secrets-version",
+        value_name = "VERSION",
+        env = "VAULT_SECRETS_VERSION"
+    )]
+    secrets_version: Option<u64>,
+    /// set the version of the service's consul config
+    #[structopt(
+        long = "config-version",
+        value_name = "VERSION",
+        env = "CONSUL_CONFIG_VERSION"
+    )]
+    config_version: Option<u64>,
+    /// authenticate with vault approle
+    #[structopt(
+        long = "role-id",
+        value_name = "ROLE_ID",
+        conflicts_with = "dev",
+        env = "VAULT_ROLE_ID"
+    )]
+    role_id: Option<String>,
+    /// set the vault approle secret-id
+    #[structopt(
+        long = "secret-id",
+        value_name = "SECRET_ID",
+        requires = "role-id",
+        env = "VAULT_SECRET_ID"
+    )]
+    secret_id: Option<Secret>,
+    /// read the vault approle secret-id from a file
+    #[structopt(
+        long = "secret-id-file",
+        value_name = "FILE",
+        requires = "role-id",
+        env = "VAULT_SECRET_ID_FILE"
+    )]
+    secret_id_file: Option<PathBuf>,
+    /// authenticate with vault kubernetes auth
+    #[structopt(
+        long = "kubernetes-role",
+        value_name = "ROLE",
+        conflicts_with = "dev",
+        env = "VAULT_KUBERNETES_ROLE"
+    )]
+    kubernetes_role: Option<String>,
+    /// set the vault kubernetes auth mount path
+    #[structopt(
+        long = "kubernetes-mount",
+        value_name = "PATH",
+        default_value = "kubernetes",
+        env = "VAULT_KUBERNETES_MOUNT"
+    )]
+    kubernetes_mount: String,
+    /// set the kubernetes service account token file
+    #[structopt(
+        long = "kubernetes-token-file",
+        value_name = "FILE",
+        default_value = "/var/run/secrets/kubernetes.io/serviceaccount/token",
+        env = "VAULT_KUBERNETES_TOKEN_FILE"
+    )]
+    kubernetes_token_file: PathBuf,
+    /// issue a certificate from vault pki as a role
+    #[structopt(long = "pki-role", value_name = "ROLE", env = "VAULT_PKI_ROLE")]
+    pki_role: Option<String>,
+    /// set the vault pki mount path
+    #[structopt(
+        long = "pki-mount",
+        value_name = "PATH",
+        default_value = "pki",
+        env = "VAULT_PKI_MOUNT"
+    )]
+    pki_mount: String,
+    /// set the certificate common name, defaulting to the service name
+    #[structopt(
+        long = "pki-common-name",
+        value_name = "NAME",
+        env = "VAULT_PKI_COMMON_NAME"
+    )]
+    pki_common_name: Option<String>,
+    /// set the file to write the certificate to
+    #[structopt(long = "pki-cert-file", value_name = "FILE", default_value = "tls.crt")]
+    pki_cert_file: PathBuf,
+    /// set the file to write the private key to
+    #[structopt(long = "pki-key-file", value_name = "FILE", default_value = "tls.key")]
+    pki_key_file: PathBuf,
+    /// set the file to write the CA chain to
+    #[structopt(long = "pki-ca-file", value_name = "FILE", default_value = "ca.crt")]
+    pki_ca_file: PathBuf,
+    /// set the vault transit key to decrypt values with, defaulting to the
+    /// service name
+    #[structopt(long = "transit-key", value_name = "KEY", env = "VAULT_TRANSIT_KEY")]
+    transit_key: Option<String>,
+    /// set the vault transit mount path
+    #[structopt(
+        long = "transit-mount",
+        value_name = "PATH",
+        default_value = "transit",
+        env = "VAULT_TRANSIT_MOUNT"
+    )]
+    transit_mount: String,
+    /// replace ${NAME} in values with the value of NAME [env: AVVOENV_INTERPOLATE=]
+    #[structopt(long = "interpolate")]
+    interpolate: bool,
+    /// interpolate, erroring on unset references [env: AVVOENV_INTERPOLATE_STRICT=]
+    #[structopt(long = "interpolate-strict")]
+    interpolate_strict: bool,
+    /// validate the fetched environment against a schema, defaulting to
+    /// avvoenv.schema if it exists
+    #[structopt(long = "schema", value_name = "FILE", env = "AVVOENV_SCHEMA")]
+    schema: Option<PathBuf>,
+    /// don't validate the fetched environment against a schema
+    #[structopt(long = "no-schema", conflicts_with = "schema")]
+    no_schema: bool,
     /// [env: NO_RANCHER_METADATA=]
     #[structopt(long = "no-rancher-metadata")]
     skip_rancher_metadata: bool,
+    /// set the seconds to wait to connect to consul, vault, and rancher
+    #[structopt(
+        long = "http-connect-timeout",
+        value_name = "SECONDS",
+        default_value = "10",
+        env = "AVVOENV_HTTP_CONNECT_TIMEOUT"
+    )]
+    http_connect_timeout: u64,
+    /// set the seconds to wait for a response from consul, vault, and rancher
+    #[structopt(
+        long = "http-timeout",
+        value_name = "SECONDS",
+        default_value = "60",
+        env = "AVVOENV_HTTP_TIMEOUT"
+    )]
+    http_timeout: u64,
+    /// set the most times to try each request
+    #[structopt(
+        long = "http-max-attempts",
+        value_name = "COUNT",
+        default_value = "5",
+        env = "AVVOENV_HTTP_MAX_ATTEMPTS"
+    )]
+    http_max_attempts: u32,
+    /// set the most seconds to wait before retrying a request
+    #[structopt(
+        long = "http-max-backoff",
+        value_name = "SECONDS",
+        default_value = "30",
+        env = "AVVOENV_HTTP_MAX_BACKOFF"
+    )]
+    http_max_backoff: u64,
+    /// cache the fetched environment in a directory, to fall back to when unreachable
+    #[structopt(long = "cache-dir", value_name = "DIR", env = "AVVOENV_CACHE_DIR")]
+    cache_dir: Option<PathBuf>,
+    /// read the key to encrypt the cache with from a file
+    #[structopt(
+        long = "cache-key-file",
+        value_name = "FILE",
+        env = "AVVOENV_CACHE_KEY_FILE"
+    )]
+    cache_key_file: Option<PathBuf>,
+    /// set the max seconds old a cached environment can be to fall back to
+    #[structopt(
+        long = "cache-max-age",
+        value_name = "SECONDS",
+        default_value = "86400",
+        env = "AVVOENV_CACHE_MAX_AGE"
+    )]
+    cache_max_age: u64,
 }
 
 fn parse_add(s: &str) -> (String, String) {
@@ -189,6 +585,37 @@ struct ExecOpts {
     /// ignore the inherited env when executing <command>
     #[structopt(short = "I", long = "isolate")]
     isolate: bool,
+    /// run <command> as a child process rather than replacing avvoenv
+    #[structopt(long = "no-replace")]
+    no_replace: bool,
+    /// restart <command> when the fetched environment changes
+    #[structopt(short = "w", long = "watch")]
+    watch: bool,
+    /// set the max seconds between checks for changes
+    #[structopt(
+        long = "watch-interval",
+        value_name = "SECONDS",
+        default_value = "30",
+        env = "AVVOENV_WATCH_INTERVAL"
+    )]
+    watch_interval: u64,
+    /// send a signal to <command> on changes rather than restarting it
+    #[structopt(long = "reload-signal", value_name = "SIGNAL", requires = "watch")]
+    reload_signal: Option<supervisor::Signal>,
+    /// write the fetched environment to a file, rewriting it on changes
+    #[structopt(long = "reload-file", value_name = "FILE")]
+    reload_file: Option<PathBuf>,
+    /// set the --reload-file format
+    #[structopt(
+        long = "reload-format",
+        value_name = "FORMAT",
+        requires = "reload-file",
+        possible_values = &["env", "defaults", "hcon", "json", "properties", "yaml"]
+    )]
+    reload_format: Option<Format>,
+    /// set the seconds to wait for <command> to stop before killing it
+    #[structopt(long = "stop-timeout", value_name = "SECONDS", default_value = "10")]
+    stop_timeout: u64,
     /// Command to exec
     #[structopt(name = "CMD")]
     cmd: Vec<String>,
@@ -203,29 +630,69 @@ fn exec(opts: ExecOpts) -> Result<(), Box<dyn std::error::Error>> {
         std::process::exit(1);
     }
 
-    let mut command = std::process::Command::new(&opts.cmd[0]);
-    command.args(&opts.cmd[1..]);
-
-    if opts.isolate {
-        debug!("Clearing inherited system environment due to --isolate option");
-        command.env_clear();
-    }
-
-    match env::fetch(opts.fetch) {
-        Ok(env) => {
-            trace!("Got env: {:#?}", env);
-            command.envs(env);
+    let cache = env::cache(&opts.fetch)?;
+    let (fetcher, result) = match env::Fetcher::new(opts.fetch) {
+        Ok(mut fetcher) => {
+            let result = fetcher.fetch();
+            (Some(fetcher), result)
         }
+        Err(e) => (None, Err(e)),
+    };
+    let env = match result.or_else(|e| env::fallback(e, cache.as_ref())) {
+        Ok(env) => Some(env),
         Err(ref e) if opts.force => {
             debug!("{:?}", e);
             warn!("{}", e);
             debug!("Ignoring error due to --force option");
+            None
         }
         Err(e) => return Err(e.into()),
     };
+    trace!("Got env: {:#?}", env);
 
     debug!("Executing {:?}", &opts.cmd[0]);
     trace!("Args: {:?}", &opts.cmd[1..]);
+    let cmd = opts.cmd;
+    let isolate = opts.isolate;
+    let command = move |env: Option<&HashMap<String, String>>| {
+        let mut command = Command::new(&cmd[0]);
+        command.args(&cmd[1..]);
+        if isolate {
+            debug!("Clearing inherited system environment due to --isolate option");
+            command.env_clear();
+        }
+        if let Some(env) = env {
+            command.envs(env);
+        }
+        command
+    };
+
+    if opts.no_replace || opts.watch || opts.reload_file.is_some() {
+        let mut supervisor =
+            supervisor::Supervisor::new(command, Duration::from_secs(opts.stop_timeout));
+        if opts.watch {
+            debug!("Watching for changes due to --watch option");
+            supervisor.watch(Duration::from_secs(opts.watch_interval));
+        }
+        if let Some(signal) = opts.reload_signal {
+            debug!("Reloading with {:?} due to --reload-signal option", signal);
+            supervisor.reload_signal(signal);
+        }
+        if let Some(path) = opts.reload_file {
+            let format = opts
+                .reload_format
+                .unwrap_or_else(|| Format::from_path(&path));
+            debug!(
+                "Writing {:?} as {:?} due to --reload-file option",
+                path, format
+            );
+            supervisor.reload_file(path, format);
+        }
+        let code = supervisor.run(fetcher, env)?;
+        std::process::exit(code);
+    }
+
+    let mut command = command(env.as_ref());
     Err(Box::new(command.exec()))
 }
 
@@ -264,8 +731,221 @@ fn write(opts: WriteOpts) -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[derive(StructOpt, Debug)]
+#[structopt(group = ArgGroup::with_name("to").required(true).multiple(true))]
+struct DiffOpts {
+    #[structopt(flatten)]
+    fetch: FetchOpts,
+    /// compare with another service
+    #[structopt(long = "to-service", value_name = "NAME", group = "to")]
+    to_service: Option<String>,
+    /// compare with another consul host
+    #[structopt(long = "to-consul", value_name = "URL", group = "to")]
+    to_consul: Option<Url>,
+    /// set the consul ACL token for the compared environment
+    #[structopt(long = "to-consul-token", value_name = "TOKEN", group = "to")]
+    to_consul_token: Option<Secret>,
+    /// compare with another vault host
+    #[structopt(long = "to-vault", value_name = "URL", group = "to")]
+    to_vault: Option<Url>,
+    /// set the vault token for the compared environment
+    #[structopt(long = "to-vault-token", value_name = "TOKEN", group = "to")]
+    to_vault_token: Option<Secret>,
+    /// compare with another version of the service's consul config
+    #[structopt(long = "to-config-version", value_name = "VERSION", group = "to")]
+    to_config_version: Option<u64>,
+    /// compare with another version of the service's vault secrets
+    #[structopt(long = "to-secrets-version", value_name = "VERSION", group = "to")]
+    to_secrets_version: Option<u64>,
+    /// show values rather than their hashes
+    #[structopt(long = "reveal")]
+    reveal: bool,
+}
+
+fn diff(opts: DiffOpts) -> Result<(), Box<dyn std::error::Error>> {
+    trace!("Running diff subcommand");
+
+    let mut from = opts.fetch;
+    // each side is fetched from its own cluster, don't mix them in one cache
+    from.cache_dir = None;
+    // differences are worth seeing whether or not either side is valid
+    from.schema = None;
+    from.no_schema = true;
+    let mut to = from.clone();
+    if let Some(service) = opts.to_service {
+        to.service = Some(service);
+    }
+    if let Some(url) = opts.to_consul {
+        to.consul = Some(url);
+    }
+    if let Some(token) = opts.to_consul_token {
+        to.consul_token_file = None;
+        to.consul_token = Some(token);
+    }
+    if let Some(url) = opts.to_vault {
+        to.vault = Some(url);
+    }
+    if let Some(token) = opts.to_vault_token {
+        to.dev = false;
+        to.role_id = None;
+        to.kubernetes_role = None;
+        to.app_id = None;
+        to.wrapped_token = None;
+        to.token = Some(token);
+    }
+    if let Some(version) = opts.to_config_version {
+        to.config_version = Some(version);
+    }
+    if let Some(version) = opts.to_secrets_version {
+        to.secrets_version = Some(version);
+    }
+
+    debug!("Fetching environment to compare from");
+    let from = env::inspect(from)?;
+    trace!("Got env: {:#?}", from);
+    debug!("Fetching environment to compare to");
+    let to = env::inspect(to)?;
+    trace!("Got env: {:#?}", to);
+    if !diff::to_writer(io::stdout(), &from, &to, opts.reveal)? {
+        info!("No differences");
+    }
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+struct ExplainOpts {
+    #[structopt(flatten)]
+    fetch: FetchOpts,
+    /// write JSON rather than a table
+    #[structopt(long = "json")]
+    json: bool,
+    /// show values rather than their hashes
+    #[structopt(long = "reveal")]
+    reveal: bool,
+    /// only explain these variables
+    #[structopt(value_name = "NAME")]
+    names: Vec<String>,
+}
+
+fn explain(opts: ExplainOpts) -> Result<(), Box<dyn std::error::Error>> {
+    trace!("Running explain subcommand");
+
+    let mut fetch = opts.fetch;
+    // explaining needs the real sources, not whatever was last cached
+    fetch.cache_dir = None;
+    let merged = env::explain(fetch)?;
+    trace!("Got env: {:#?}", merged);
+    for name in &opts.names {
+        if !merged.contains(name) {
+            warn!("{} is not set", name);
+        }
+    }
+    if opts.json {
+        merged.to_json_writer(io::stdout(), &opts.names, opts.reveal)?;
+        println!();
+    } else {
+        merged.to_writer(io::stdout(), &opts.names, opts.reveal)?;
+    }
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+struct GetOpts {
+    #[structopt(flatten)]
+    fetch: FetchOpts,
+    /// print this for variables that aren't set, rather than erroring
+    #[structopt(long = "default", value_name = "VALUE")]
+    default: Option<String>,
+    /// print KEY=VALUE rather than just the value
+    #[structopt(long = "with-keys")]
+    with_keys: bool,
+    /// print these variables
+    #[structopt(name = "KEY", required = true)]
+    keys: Vec<String>,
+}
+
+fn get(opts: GetOpts) -> Result<(), Box<dyn std::error::Error>> {
+    trace!("Running get subcommand");
+
+    let env = env::get(opts.fetch, &opts.keys)?;
+    trace!("Got env: {:#?}", env);
+    let mut values = Vec::with_capacity(opts.keys.len());
+    let mut missing = Vec::new();
+    for key in &opts.keys {
+        match env.get(key).or(opts.default.as_ref()) {
+            Some(value) => values.push((key, value)),
+            None => missing.push(key.as_str()),
+        }
+    }
+    if !missing.is_empty() {
+        return Err(format!("not set: {}", missing.join(", ")).into());
+    }
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+    for (key, value) in values {
+        if opts.with_keys {
+            writeln!(stdout, "{}={}", key, value)?;
+        } else {
+            writeln!(stdout, "{}", value)?;
+        }
+    }
+    Ok(())
+}
+
+#[derive(StructOpt, Debug)]
+#[structopt(group = ArgGroup::with_name("changes").required(true).multiple(true))]
+struct SetOpts {
+    #[structopt(flatten)]
+    fetch: FetchOpts,
+    /// set a secret, stored in vault rather than consul
+    #[structopt(
+        short = "S",
+        long = "secret",
+        value_name = "KEY=VALUE",
+        parse(from_str = parse_add),
+        group = "changes"
+    )]
+    secrets: Vec<(String, String)>,
+    /// remove a variable from the config and secrets
+    #[structopt(long = "unset", value_name = "KEY", group = "changes")]
+    unset: Vec<String>,
+    /// set the user to record as publishing the change
+    #[structopt(long = "user", value_name = "NAME", env = "USER")]
+    user: String,
+    /// set these variables in the config
+    #[structopt(name = "KEY=VALUE", parse(from_str = parse_add), group = "changes")]
+    config: Vec<(String, String)>,
+}
+
+fn set(opts: SetOpts) -> Result<(), Box<dyn std::error::Error>> {
+    trace!("Running set subcommand");
+
+    let config = env::Changes {
+        set: opts.config,
+        unset: opts.unset.clone(),
+    };
+    let secrets = env::Changes {
+        set: opts.secrets,
+        unset: opts.unset,
+    };
+    let fetcher = env::Fetcher::new(opts.fetch)?;
+    let published = fetcher.publish(&config, &secrets, &opts.user)?;
+    match published.config {
+        Some(version) => println!("Published config version {}", version),
+        None => println!("No changes to config"),
+    }
+    match published.secrets {
+        Some(version) => println!("Published secrets version {}", version),
+        None => println!("No changes to secrets"),
+    }
+    Ok(())
+}
+
 #[derive(StructOpt, Debug)]
 struct ServiceOpts {
+    /// use the options from a profile in the config files
+    #[structopt(long = "profile", value_name = "NAME", env = "AVVOENV_PROFILE")]
+    profile: Option<String>,
     /// set the service name
     #[structopt(short = "s", long = "service", value_name = "NAME", env = "SERVICE")]
     service: Option<String>,
@@ -274,6 +954,9 @@ struct ServiceOpts {
 fn service(opts: ServiceOpts) -> Result<(), Box<dyn std::error::Error>> {
     trace!("Running service subcommand");
 
+    if let Some(profile) = opts.profile {
+        debug!("Using profile {:?}", profile);
+    }
     let service = service::name(opts.service)?;
     println!("{}", service);
     Ok(())
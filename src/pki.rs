@@ -0,0 +1,168 @@
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use log::{debug, info};
+
+use crate::{file::write_private, vault};
+
+#[derive(Debug)]
+pub enum Error {
+    IoError(io::Error),
+    VaultError(vault::Error),
+}
+
+impl Error {
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Error::VaultError(e) => e.is_unavailable(),
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::IoError(e) => e.fmt(f),
+            Error::VaultError(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::IoError(e) => Some(e),
+            Error::VaultError(e) => Some(e),
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Error {
+        Error::IoError(e)
+    }
+}
+
+impl From<vault::Error> for Error {
+    fn from(e: vault::Error) -> Error {
+        Error::VaultError(e)
+    }
+}
+
+/// A certificate issued by a Vault PKI secrets engine, written out to files
+/// for the service to use.
+#[derive(Debug)]
+pub struct Certificate {
+    mount: String,
+    role: String,
+    common_name: String,
+    cert_file: PathBuf,
+    key_file: PathBuf,
+    ca_file: PathBuf,
+    issued: bool,
+    renew_at: Option<Instant>,
+}
+
+impl Certificate {
+    pub fn new(
+        mount: &str,
+        role: String,
+        common_name: String,
+        cert_file: PathBuf,
+        key_file: PathBuf,
+        ca_file: PathBuf,
+    ) -> Certificate {
+        Certificate {
+            mount: mount.trim_matches('/').to_owned(),
+            role,
+            common_name,
+            cert_file,
+            key_file,
+            ca_file,
+            issued: false,
+            renew_at: None,
+        }
+    }
+
+    /// Issues the certificate, unless it's already been issued, returning the
+    /// environment variables with the paths of the files.
+    pub fn env(&mut self, client: &vault::Client) -> Result<Vec<(String, String)>, Error> {
+        if !self.issued {
+            self.issue(client)?;
+        }
+        Ok(vec![
+            ("TLS_CERT_FILE".to_owned(), path_string(&self.cert_file)),
+            ("TLS_KEY_FILE".to_owned(), path_string(&self.key_file)),
+            ("TLS_CA_FILE".to_owned(), path_string(&self.ca_file)),
+        ])
+    }
+
+    /// Issues a new certificate if two thirds of the current certificate's
+    /// validity has passed, returning `true` if the files were rewritten.
+    pub fn renew(&mut self, client: &vault::Client) -> Result<bool, Error> {
+        match self.renew_at {
+            Some(at) if self.issued && at <= Instant::now() => {
+                info!("Reissuing certificate for {:?}", self.common_name);
+                self.issue(client)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// The Vault path the certificate is issued from.
+    pub fn issue_path(&self) -> String {
+        format!("{}/issue/{}", self.mount, self.role)
+    }
+
+    /// When the certificate is next due to be reissued.
+    pub fn renew_at(&self) -> Option<Instant> {
+        self.renew_at
+    }
+
+    fn issue(&mut self, client: &vault::Client) -> Result<(), Error> {
+        debug!(
+            "Issuing certificate for {:?} from {}/issue/{}",
+            self.common_name, self.mount, self.role
+        );
+        let cert = client.issue_certificate(&self.mount, &self.role, &self.common_name)?;
+        let mut ca = cert.ca_chain.join("\n");
+        if ca.is_empty() {
+            ca = cert.issuing_ca;
+        }
+        write(&self.cert_file, &cert.certificate)?;
+        write(&self.key_file, &cert.private_key)?;
+        write(&self.ca_file, &ca)?;
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        let validity = Duration::from_secs(cert.expiration.saturating_sub(now));
+        debug!(
+            "Certificate {} valid for {:?}",
+            cert.serial_number, validity
+        );
+        self.issued = true;
+        self.renew_at = Some(Instant::now() + validity * 2 / 3);
+        Ok(())
+    }
+}
+
+/// Writes a PEM file readable only by the current user, ending it with a
+/// newline.
+fn write(path: &Path, contents: &str) -> io::Result<()> {
+    debug!("Writing {:?}", path);
+    let mut contents = contents.to_owned();
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    write_private(path, contents.as_bytes())
+}
+
+fn path_string(path: &Path) -> String {
+    path.to_string_lossy().into_owned()
+}
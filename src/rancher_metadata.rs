@@ -1,10 +1,10 @@
-use std::{fmt, iter::IntoIterator, net::ToSocketAddrs, thread::sleep, time::Duration};
+use std::{fmt, iter::IntoIterator, net::ToSocketAddrs};
 
 use log::trace;
 use reqwest::Url;
 use serde::Deserialize;
 
-use crate::client_error::ClientError;
+use crate::{client_error::ClientError, http};
 
 pub fn is_available() -> bool {
     "rancher-metadata:80".to_socket_addrs().is_ok()
@@ -12,7 +12,7 @@ pub fn is_available() -> bool {
 
 pub struct Client {
     address: Url,
-    http: reqwest::blocking::Client,
+    http: http::Client,
 }
 
 #[derive(Deserialize)]
@@ -72,6 +72,12 @@ impl Iterator for IntoIter {
 #[derive(Debug)]
 pub struct Error(ClientError);
 
+impl Error {
+    pub fn is_unavailable(&self) -> bool {
+        self.0.is_unavailable()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -91,11 +97,10 @@ impl<T: Into<ClientError>> From<T> for Error {
 }
 
 impl Client {
-    pub fn new() -> Client {
-        Client {
-            address: "http://rancher-metadata/2015-12-19/".parse().unwrap(),
-            http: reqwest::blocking::Client::new(),
-        }
+    pub fn new(retry: &http::Retry) -> Result<Client, Error> {
+        let mut address = "http://rancher-metadata/2015-12-19/".parse().unwrap();
+        let http = http::client(&mut address, &http::Tls::default(), retry)?;
+        Ok(Client { address, http })
     }
 
     fn get<T>(&self, path: &str) -> Result<Option<T>, Error>
@@ -108,8 +113,9 @@ impl Client {
             .get(url.clone())
             .header(reqwest::header::ACCEPT, "application/json");
         trace!("{:?}", request);
-        let response = request
-            .send()
+        let response = self
+            .http
+            .send(request)
             .map_err(|e| ClientError::with_url(url.clone(), e))?;
         trace!("{:?}", response);
         if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -121,26 +127,7 @@ impl Client {
         Ok(response.json().map_err(|e| ClientError::with_url(url, e))?)
     }
 
-    fn get_retry<T>(&self, path: &str) -> Result<Option<T>, Error>
-    where
-        T: serde::de::DeserializeOwned + 'static,
-    {
-        let mut tries = 0;
-        loop {
-            match self.get(path) {
-                Ok(v) => return Ok(v),
-                Err(e) => {
-                    tries += 1;
-                    if tries > 5 {
-                        return Err(e);
-                    }
-                    sleep(Duration::from_secs(tries));
-                }
-            }
-        }
-    }
-
     pub fn info(&self) -> Result<Option<Info>, Error> {
-        self.get_retry("self")
+        self.get("self")
     }
 }
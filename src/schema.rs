@@ -0,0 +1,268 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use regex::Regex;
+use reqwest::Url;
+use serde::Deserialize;
+
+/// The schema file looked for in the current directory when `--schema`
+/// isn't given.
+pub const DEFAULT_FILE: &str = "avvoenv.schema";
+
+#[derive(Debug)]
+pub enum Error {
+    EnumError(PathBuf, String),
+    InvalidError(PathBuf, Vec<Violation>),
+    IoError(PathBuf, io::Error),
+    PatternError(PathBuf, String, regex::Error),
+    YamlError(PathBuf, serde_yaml::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::EnumError(path, key) => {
+                write!(f, "{} in {:?} is an enum, but has no values", key, path)
+            }
+            Error::InvalidError(path, violations) => {
+                write!(f, "environment doesn't match {:?}:", path)?;
+                for violation in violations {
+                    write!(f, "\n  {}", violation)?;
+                }
+                Ok(())
+            }
+            Error::IoError(path, e) => write!(f, "could not read {:?}: {}", path, e),
+            Error::PatternError(path, key, e) => {
+                write!(f, "invalid pattern for {} in {:?}: {}", key, path, e)
+            }
+            Error::YamlError(path, e) => write!(f, "invalid schema in {:?}: {}", path, e),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::EnumError(..) | Error::InvalidError(..) => None,
+            Error::IoError(_, e) => Some(e),
+            Error::PatternError(_, _, e) => Some(e),
+            Error::YamlError(_, e) => Some(e),
+        }
+    }
+}
+
+/// A variable that doesn't match the schema. Values aren't included, as
+/// they may be secret.
+#[derive(Debug)]
+pub struct Violation {
+    key: String,
+    problem: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.key, self.problem)
+    }
+}
+
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Type {
+    #[default]
+    String,
+    Int,
+    Bool,
+    Url,
+    Duration,
+    Enum,
+}
+
+/// What's expected of a single variable.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct Spec {
+    #[serde(default, rename = "type")]
+    kind: Type,
+    #[serde(default)]
+    required: bool,
+    #[serde(default)]
+    pattern: Option<String>,
+    #[serde(default)]
+    values: Vec<String>,
+    #[serde(default)]
+    default: Option<String>,
+}
+
+/// The variables a service expects, loaded from a YAML (or JSON) file mapping
+/// each name to its type, whether it's required, a regex it must match, and
+/// a default.
+#[derive(Debug)]
+pub struct Schema {
+    path: PathBuf,
+    specs: BTreeMap<String, Spec>,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    pub fn load(path: &Path) -> Result<Schema, Error> {
+        debug!("Reading schema from {:?}", path);
+        let contents = fs::read(path).map_err(|e| Error::IoError(path.to_owned(), e))?;
+        let specs: BTreeMap<String, Spec> =
+            serde_yaml::from_slice(&contents).map_err(|e| Error::YamlError(path.to_owned(), e))?;
+        let mut patterns = HashMap::new();
+        for (key, spec) in &specs {
+            if spec.kind == Type::Enum && spec.values.is_empty() {
+                return Err(Error::EnumError(path.to_owned(), key.clone()));
+            }
+            if let Some(ref pattern) = spec.pattern {
+                let regex = Regex::new(pattern)
+                    .map_err(|e| Error::PatternError(path.to_owned(), key.clone(), e))?;
+                patterns.insert(key.clone(), regex);
+            }
+        }
+        Ok(Schema {
+            path: path.to_owned(),
+            specs,
+            patterns,
+        })
+    }
+
+    /// The path the schema was loaded from.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The defaults for the variables for which `is_set` is false.
+    pub fn defaults<F: Fn(&str) -> bool>(&self, is_set: F) -> Vec<(String, String)> {
+        self.specs
+            .iter()
+            .filter(|(key, _)| !is_set(key))
+            .filter_map(|(key, spec)| Some((key.clone(), spec.default.clone()?)))
+            .collect()
+    }
+
+    /// Checks `env` against the schema, returning every violation rather
+    /// than just the first.
+    pub fn validate(&self, env: &HashMap<String, String>) -> Result<(), Error> {
+        let mut violations = Vec::new();
+        for (key, spec) in &self.specs {
+            let value = match env.get(key) {
+                Some(value) => value,
+                None if spec.required => {
+                    violations.push(Violation {
+                        key: key.clone(),
+                        problem: "required, but not set".to_owned(),
+                    });
+                    continue;
+                }
+                None => continue,
+            };
+            if !spec.matches_type(value) {
+                let problem = match spec.kind {
+                    Type::Enum => format!("not one of {}", spec.values.join(", ")),
+                    kind => format!("not a valid {:?}", kind).to_lowercase(),
+                };
+                violations.push(Violation {
+                    key: key.clone(),
+                    problem,
+                });
+            }
+            if let Some(regex) = self.patterns.get(key) {
+                if !regex.is_match(value) {
+                    violations.push(Violation {
+                        key: key.clone(),
+                        problem: format!("doesn't match {:?}", regex.as_str()),
+                    });
+                }
+            }
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(Error::InvalidError(self.path.clone(), violations))
+        }
+    }
+}
+
+impl Spec {
+    fn matches_type(&self, value: &str) -> bool {
+        match self.kind {
+            Type::String => true,
+            Type::Int => value.parse::<i64>().is_ok(),
+            Type::Bool => matches!(
+                value.to_lowercase().as_str(),
+                "true" | "false" | "1" | "0" | "yes" | "no"
+            ),
+            Type::Url => Url::parse(value).is_ok(),
+            Type::Duration => is_duration(value),
+            Type::Enum => self.values.iter().any(|v| v == value),
+        }
+    }
+}
+
+/// Whether `value` is a duration, either a number of seconds, or numbers
+/// each followed by a unit, `ms`, `s`, `m`, `h`, or `d`, such as `1h30m`.
+fn is_duration(value: &str) -> bool {
+    if !value.is_empty() && value.bytes().all(|b| b.is_ascii_digit()) {
+        return true;
+    }
+    let mut rest = value;
+    while !rest.is_empty() {
+        let digits = rest.bytes().take_while(u8::is_ascii_digit).count();
+        if digits == 0 {
+            return false;
+        }
+        rest = &rest[digits..];
+        let unit = ["ms", "s", "m", "h", "d"]
+            .iter()
+            .find(|unit| rest.starts_with(*unit));
+        match unit {
+            Some(unit) => rest = &rest[unit.len()..],
+            None => return false,
+        }
+    }
+    !value.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spec(kind: Type) -> Spec {
+        Spec {
+            kind,
+            required: false,
+            pattern: None,
+            values: vec!["debug".to_owned(), "info".to_owned()],
+            default: None,
+        }
+    }
+
+    #[test]
+    fn durations() {
+        for value in &["30", "0", "30s", "1h30m", "250ms", "2d", "1m1s"] {
+            assert!(is_duration(value), "{:?}", value);
+        }
+        for value in &["", "s", "1.5s", "1x", "h1", "1h 30m", "-1", "30s1"] {
+            assert!(!is_duration(value), "{:?}", value);
+        }
+    }
+
+    #[test]
+    fn types() {
+        assert!(spec(Type::String).matches_type(""));
+        assert!(spec(Type::Int).matches_type("-42"));
+        assert!(!spec(Type::Int).matches_type("4.2"));
+        assert!(spec(Type::Bool).matches_type("Yes"));
+        assert!(!spec(Type::Bool).matches_type("on"));
+        assert!(spec(Type::Url).matches_type("postgres://db:5432/app"));
+        assert!(!spec(Type::Url).matches_type("/app"));
+        assert!(spec(Type::Duration).matches_type("1h"));
+        assert!(spec(Type::Enum).matches_type("info"));
+        assert!(!spec(Type::Enum).matches_type("INFO"));
+    }
+}
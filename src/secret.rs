@@ -0,0 +1,33 @@
+use std::{fmt, str::FromStr};
+
+/// A string that is redacted from `Debug` output, for tokens, passwords and
+/// the like.
+#[derive(Clone)]
+pub struct Secret(pub(crate) String);
+
+#[derive(Debug)]
+pub enum ParseError {}
+
+impl std::error::Error for ParseError {}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
+        unreachable!()
+    }
+}
+
+impl FromStr for Secret {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Secret(s.to_owned()))
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_tuple("Secret")
+            .field(&"*".repeat(self.0.len()))
+            .finish()
+    }
+}
@@ -0,0 +1,165 @@
+//! A stub HTTP server for tests, answering requests with canned responses,
+//! and recording them so tests can check what was sent.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use reqwest::Url;
+
+use crate::http;
+
+/// A request received by the stub.
+#[derive(Clone, Debug)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    /// The headers, with lowercase names.
+    pub headers: HashMap<String, String>,
+    pub body: String,
+}
+
+impl Request {
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).map(String::as_str)
+    }
+
+    pub fn json(&self) -> serde_json::Value {
+        serde_json::from_str(&self.body).unwrap()
+    }
+}
+
+/// A response from the stub, as a status and a JSON body.
+pub type Response = (u16, serde_json::Value);
+
+pub struct Stub {
+    pub address: Url,
+    requests: Arc<Mutex<Vec<Request>>>,
+}
+
+impl Stub {
+    /// Starts a stub serving plain HTTP, answering each request with
+    /// `handler`.
+    pub fn new<F>(handler: F) -> Stub
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        Stub::start("http", handler, Some)
+    }
+
+    /// Starts a stub serving HTTPS with the self-signed certificate for
+    /// `vault.test` in `tests/fixtures`, answering each request with
+    /// `handler`.
+    pub fn tls<F>(handler: F) -> Stub
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        let identity = native_tls::Identity::from_pkcs8(
+            &std::fs::read(fixture("vault.test.crt")).unwrap(),
+            &std::fs::read(fixture("vault.test.key")).unwrap(),
+        )
+        .unwrap();
+        let acceptor = native_tls::TlsAcceptor::new(identity).unwrap();
+        // handshakes the client rejects fail here, and are dropped
+        Stub::start("https", handler, move |stream| acceptor.accept(stream).ok())
+    }
+
+    fn start<F, A, S>(scheme: &str, handler: F, accept: A) -> Stub
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+        A: Fn(TcpStream) -> Option<S> + Send + 'static,
+        S: Read + Write,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("{}://{}/", scheme, listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = match stream.ok().and_then(&accept) {
+                    Some(stream) => stream,
+                    None => continue,
+                };
+                if let Some(request) = read_request(&mut stream) {
+                    let (status, body) = handler(&request);
+                    received.lock().unwrap().push(request);
+                    let body = body.to_string();
+                    let _ = write!(
+                        stream,
+                        "HTTP/1.1 {} Stub\r\nContent-Type: application/json\r\n\
+                         Content-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        status,
+                        body.len(),
+                        body
+                    );
+                    let _ = stream.flush();
+                }
+            }
+        });
+        Stub {
+            address: Url::parse(&address).unwrap(),
+            requests,
+        }
+    }
+
+    /// The requests received so far, oldest first.
+    pub fn requests(&self) -> Vec<Request> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+/// Timeouts for clients of the stub, without retrying, so failures show up
+/// straight away.
+pub fn retry() -> http::Retry {
+    http::Retry {
+        connect_timeout: Duration::from_secs(5),
+        timeout: Duration::from_secs(5),
+        max_attempts: 1,
+        max_backoff: Duration::from_secs(0),
+    }
+}
+
+/// The path of a file in `tests/fixtures`.
+pub fn fixture(name: &str) -> PathBuf {
+    [env!("CARGO_MANIFEST_DIR"), "tests", "fixtures", name]
+        .iter()
+        .collect()
+}
+
+fn read_request<S: Read>(stream: S) -> Option<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+    reader.read_line(&mut line).ok()?;
+    let mut parts = line.split_whitespace();
+    let method = parts.next()?.to_owned();
+    let path = parts.next()?.to_owned();
+    let mut headers = HashMap::new();
+    loop {
+        line.clear();
+        reader.read_line(&mut line).ok()?;
+        match line.trim_end().split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.to_lowercase(), value.trim().to_owned());
+            }
+            None => break,
+        }
+    }
+    let length = headers
+        .get("content-length")
+        .and_then(|l| l.parse().ok())
+        .unwrap_or(0);
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body).ok()?;
+    Some(Request {
+        method,
+        path,
+        headers,
+        body: String::from_utf8(body).ok()?,
+    })
+}
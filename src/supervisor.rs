@@ -0,0 +1,438 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, io,
+    os::unix::process::ExitStatusExt,
+    path::PathBuf,
+    process::{Command, ExitStatus},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{channel, Sender},
+        Arc, Mutex, MutexGuard, TryLockError,
+    },
+    thread::{self, sleep},
+    time::{Duration, Instant},
+};
+
+use log::{debug, error, info, trace, warn};
+use signal_hook::{
+    consts::{SIGCHLD, SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2},
+    iterator::Signals,
+};
+
+use crate::{
+    env::{Fetcher, Renewal},
+    file::write_private,
+    format::Format,
+};
+
+const FORWARDED: &[i32] = &[SIGHUP, SIGINT, SIGQUIT, SIGTERM, SIGUSR1, SIGUSR2];
+
+const RENEW_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How long to wait for a fetch in progress to finish before revoking the
+/// Vault token once the child exits.
+const REVOKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+const NAMES: &[(&str, i32)] = &[
+    ("HUP", libc::SIGHUP),
+    ("INT", libc::SIGINT),
+    ("QUIT", libc::SIGQUIT),
+    ("KILL", libc::SIGKILL),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+    ("ALRM", libc::SIGALRM),
+    ("TERM", libc::SIGTERM),
+    ("CONT", libc::SIGCONT),
+    ("STOP", libc::SIGSTOP),
+    ("TTIN", libc::SIGTTIN),
+    ("TTOU", libc::SIGTTOU),
+    ("WINCH", libc::SIGWINCH),
+];
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Signal(i32);
+
+#[derive(Debug)]
+pub struct ParseSignalError(String);
+
+impl fmt::Display for ParseSignalError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unknown signal {:?}", self.0)
+    }
+}
+
+impl Error for ParseSignalError {}
+
+impl FromStr for Signal {
+    type Err = ParseSignalError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(number) = s.parse() {
+            return Ok(Signal(number));
+        }
+        let upper = s.trim().to_uppercase();
+        let name = upper.trim_start_matches("SIG");
+        NAMES
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, number)| Signal(*number))
+            .ok_or_else(|| ParseSignalError(s.to_owned()))
+    }
+}
+
+enum Event {
+    Signal(i32),
+    Changed(HashMap<String, String>),
+    Reissued,
+}
+
+/// Runs a command as a child process, forwarding signals to it, and
+/// optionally restarting or reloading it whenever the fetched environment
+/// changes.
+pub(crate) struct Supervisor<F> {
+    command: F,
+    interval: Option<Duration>,
+    stop_timeout: Duration,
+    reload_signal: Option<Signal>,
+    reload_file: Option<(PathBuf, Format)>,
+}
+
+impl<F> Supervisor<F>
+where
+    F: Fn(Option<&HashMap<String, String>>) -> Command,
+{
+    pub(crate) fn new(command: F, stop_timeout: Duration) -> Supervisor<F> {
+        Supervisor {
+            command,
+            interval: None,
+            stop_timeout,
+            reload_signal: None,
+            reload_file: None,
+        }
+    }
+
+    /// Check for changes to the environment every `interval`.
+    pub(crate) fn watch(&mut self, interval: Duration) {
+        self.interval = Some(interval);
+    }
+
+    /// Send `signal` to the child when the environment changes, rather than
+    /// restarting it.
+    pub(crate) fn reload_signal(&mut self, signal: Signal) {
+        self.reload_signal = Some(signal);
+    }
+
+    /// Write the environment to `path` before starting the child, and again
+    /// each time the environment changes.
+    pub(crate) fn reload_file(&mut self, path: PathBuf, format: Format) {
+        self.reload_file = Some((path, format));
+    }
+
+    /// Runs the command until it exits, returning the exit code avvoenv
+    /// should exit with. While the command runs the Vault token is kept alive,
+    /// and once it exits the token is revoked.
+    pub(crate) fn run(
+        &self,
+        fetcher: Option<Fetcher>,
+        env: Option<HashMap<String, String>>,
+    ) -> io::Result<i32> {
+        let fetcher = fetcher.map(|fetcher| Arc::new(Mutex::new(fetcher)));
+        let stopped = Arc::new(AtomicBool::new(false));
+        let result = self.supervise(fetcher.clone(), env, stopped.clone());
+        stopped.store(true, Ordering::SeqCst);
+        if let Some(fetcher) = fetcher {
+            match lock_within(&fetcher, REVOKE_TIMEOUT) {
+                Some(mut fetcher) => {
+                    if let Err(e) = fetcher.revoke() {
+                        debug!("{:?}", e);
+                        warn!("{}", e);
+                    }
+                }
+                None => warn!("Still fetching the environment, not revoking the Vault token"),
+            }
+        }
+        result
+    }
+
+    fn supervise(
+        &self,
+        fetcher: Option<Arc<Mutex<Fetcher>>>,
+        env: Option<HashMap<String, String>>,
+        stopped: Arc<AtomicBool>,
+    ) -> io::Result<i32> {
+        let (tx, rx) = channel();
+
+        let mut signals = Signals::new(FORWARDED.iter().chain(&[SIGCHLD]))?;
+        let signal_tx = tx.clone();
+        thread::spawn(move || {
+            for signal in signals.forever() {
+                if signal_tx.send(Event::Signal(signal)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        if let Some(ref fetcher) = fetcher {
+            let fetcher = fetcher.clone();
+            // the child is only reloaded for a reissued certificate if watching
+            let tx = self.interval.map(|_| tx.clone());
+            let stopped = stopped.clone();
+            thread::spawn(move || renew(fetcher, tx, stopped));
+        }
+
+        match (self.interval, fetcher) {
+            (Some(interval), Some(fetcher)) => {
+                let last = env.clone();
+                thread::spawn(move || watch(fetcher, interval, last, tx, stopped));
+            }
+            (Some(_), None) => warn!("Unable to watch for changes to the environment"),
+            (None, _) => (),
+        }
+
+        let mut env = env;
+        if let Some(ref env) = env {
+            self.write(env)?;
+        }
+        let mut pid = self.spawn(env.as_ref())?;
+        loop {
+            match rx.recv() {
+                Ok(Event::Signal(SIGCHLD)) => {
+                    if let Some(status) = reap(pid)? {
+                        debug!("Child exited with {}", status);
+                        return Ok(exit_code(status));
+                    }
+                }
+                Ok(Event::Signal(signal)) => {
+                    debug!("Forwarding signal {} to {}", signal, pid);
+                    kill(pid, signal)?;
+                }
+                Ok(Event::Changed(changed)) => {
+                    if let Err(e) = self.write(&changed) {
+                        debug!("{:?}", e);
+                        error!("{}", e);
+                        continue;
+                    }
+                    env = Some(changed);
+                    if let Some(Signal(signal)) = self.reload_signal {
+                        info!("Environment changed, reloading");
+                        kill(pid, signal)?;
+                        continue;
+                    }
+                    info!("Environment changed, restarting");
+                    if let Some(status) = self.stop(pid)? {
+                        return Ok(exit_code(status));
+                    }
+                    pid = self.spawn(env.as_ref())?;
+                }
+                Ok(Event::Reissued) => {
+                    if let Some(Signal(signal)) = self.reload_signal {
+                        info!("Certificate reissued, reloading");
+                        kill(pid, signal)?;
+                        continue;
+                    }
+                    info!("Certificate reissued, restarting");
+                    if let Some(status) = self.stop(pid)? {
+                        return Ok(exit_code(status));
+                    }
+                    pid = self.spawn(env.as_ref())?;
+                }
+                Err(_) => {
+                    warn!("No longer handling signals, waiting for child to exit");
+                    return wait(pid).map(exit_code);
+                }
+            }
+        }
+    }
+
+    fn write(&self, env: &HashMap<String, String>) -> io::Result<()> {
+        let (path, format) = match self.reload_file {
+            Some((ref path, ref format)) => (path, format),
+            None => return Ok(()),
+        };
+        debug!("Writing {:?}", path);
+        let mut contents = Vec::new();
+        format
+            .to_writer(&mut contents, env.clone())
+            .map_err(io::Error::other)?;
+        write_private(path, &contents)
+    }
+
+    fn spawn(&self, env: Option<&HashMap<String, String>>) -> io::Result<libc::pid_t> {
+        let child = (self.command)(env).spawn()?;
+        debug!("Spawned child {}", child.id());
+        Ok(child.id() as libc::pid_t)
+    }
+
+    /// Stops the child for a restart, returning the exit status if the child
+    /// had already exited by itself.
+    fn stop(&self, pid: libc::pid_t) -> io::Result<Option<ExitStatus>> {
+        if let Some(status) = reap(pid)? {
+            return Ok(Some(status));
+        }
+        debug!("Sending SIGTERM to {}", pid);
+        kill(pid, SIGTERM)?;
+        let deadline = Instant::now() + self.stop_timeout;
+        while Instant::now() < deadline {
+            if reap(pid)?.is_some() {
+                return Ok(None);
+            }
+            sleep(Duration::from_millis(100));
+        }
+        warn!("Child did not exit within {:?}, killing", self.stop_timeout);
+        kill(pid, libc::SIGKILL)?;
+        wait(pid)?;
+        Ok(None)
+    }
+}
+
+fn watch(
+    fetcher: Arc<Mutex<Fetcher>>,
+    interval: Duration,
+    mut last: Option<HashMap<String, String>>,
+    tx: Sender<Event>,
+    stopped: Arc<AtomicBool>,
+) {
+    let mut changes = fetcher.lock().unwrap().watch(interval);
+    loop {
+        let poll = changes.poll::<serde_json::Value>();
+        // the child has exited, so the environment is no longer needed, and
+        // fetching it would hold up revoking the token
+        if stopped.load(Ordering::SeqCst) {
+            break;
+        }
+        match poll {
+            Ok(Some(_)) => debug!("Config version changed in Consul"),
+            Ok(None) => trace!("Checking for changes to the environment"),
+            Err(e) => {
+                debug!("{:?}", e);
+                warn!("{}", e);
+                continue;
+            }
+        }
+        let result = fetcher.lock().unwrap().fetch();
+        match result {
+            Ok(env) if last.as_ref() != Some(&env) => {
+                last = Some(env.clone());
+                if tx.send(Event::Changed(env)).is_err() {
+                    break;
+                }
+            }
+            Ok(_) => trace!("Environment unchanged"),
+            Err(e) => {
+                debug!("{:?}", e);
+                warn!("{}", e);
+            }
+        }
+    }
+}
+
+/// Keeps the Vault token, any dynamic secret leases, and certificate alive,
+/// checking at least every `RENEW_INTERVAL` for leases from changes to the
+/// environment. If `tx` is given the child is reloaded when the certificate is
+/// reissued.
+fn renew(fetcher: Arc<Mutex<Fetcher>>, tx: Option<Sender<Event>>, stopped: Arc<AtomicBool>) {
+    let mut failures = 0;
+    while !stopped.load(Ordering::SeqCst) {
+        let Renewal {
+            next,
+            reissued,
+            errors,
+        } = fetcher.lock().unwrap().renew();
+        if let (true, Some(ref tx)) = (reissued, &tx) {
+            if tx.send(Event::Reissued).is_err() {
+                break;
+            }
+        }
+        for e in &errors {
+            debug!("{:?}", e);
+            warn!("{}", e);
+        }
+        let delay = if errors.is_empty() {
+            failures = 0;
+            next.map_or(RENEW_INTERVAL, |at| {
+                at.saturating_duration_since(Instant::now())
+                    .min(RENEW_INTERVAL)
+            })
+        } else {
+            failures += 1;
+            fetcher.lock().unwrap().backoff(failures)
+        };
+        trace!("Renewing in {:?}", delay);
+        sleep(delay);
+    }
+}
+
+/// Locks `fetcher`, waiting up to `timeout` for another thread to finish with
+/// it, or `None` if it doesn't in time.
+fn lock_within(fetcher: &Mutex<Fetcher>, timeout: Duration) -> Option<MutexGuard<'_, Fetcher>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        match fetcher.try_lock() {
+            Ok(fetcher) => return Some(fetcher),
+            // a thread panicked while renewing or fetching, but the token
+            // should still be revoked
+            Err(TryLockError::Poisoned(e)) => return Some(e.into_inner()),
+            Err(TryLockError::WouldBlock) if Instant::now() < deadline => {
+                sleep(Duration::from_millis(100))
+            }
+            Err(TryLockError::WouldBlock) => return None,
+        }
+    }
+}
+
+/// Reaps any exited children without blocking, returning the exit status of
+/// `pid` if it was among them. When running as PID 1 this also cleans up
+/// orphaned processes that have been reparented to avvoenv.
+fn reap(pid: libc::pid_t) -> io::Result<Option<ExitStatus>> {
+    let mut result = None;
+    loop {
+        let mut status = 0;
+        match unsafe { libc::waitpid(-1, &mut status, libc::WNOHANG) } {
+            0 => break,
+            -1 => match io::Error::last_os_error() {
+                ref e if e.raw_os_error() == Some(libc::ECHILD) => break,
+                ref e if e.kind() == io::ErrorKind::Interrupted => continue,
+                e => return Err(e),
+            },
+            reaped if reaped == pid => result = Some(ExitStatus::from_raw(status)),
+            reaped => trace!("Reaped orphaned process {}", reaped),
+        }
+    }
+    Ok(result)
+}
+
+fn wait(pid: libc::pid_t) -> io::Result<ExitStatus> {
+    let mut status = 0;
+    loop {
+        if unsafe { libc::waitpid(pid, &mut status, 0) } != -1 {
+            return Ok(ExitStatus::from_raw(status));
+        }
+        let e = io::Error::last_os_error();
+        if e.kind() != io::ErrorKind::Interrupted {
+            return Err(e);
+        }
+    }
+}
+
+fn kill(pid: libc::pid_t, signal: libc::c_int) -> io::Result<()> {
+    if unsafe { libc::kill(pid, signal) } == -1 {
+        let e = io::Error::last_os_error();
+        // the child may have exited but not yet been reaped
+        if e.raw_os_error() != Some(libc::ESRCH) {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// The exit code of a child, or 128 + the signal number if it was killed by a
+/// signal, following the convention of shells.
+fn exit_code(status: ExitStatus) -> i32 {
+    match (status.code(), status.signal()) {
+        (Some(code), _) => code,
+        (None, Some(signal)) => 128 + signal,
+        (None, None) => 1,
+    }
+}
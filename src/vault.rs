@@ -1,50 +1,78 @@
-use std::{fmt, str::FromStr};
+use std::{cell::RefCell, collections::HashMap, fmt, time::Duration};
 
-use log::{debug, trace, warn};
+use log::{debug, info, trace, warn};
 use reqwest::Url;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
 
-use crate::client_error::ClientError;
+use crate::{client_error::ClientError, http, secret::Secret};
 
 #[derive(Debug)]
 pub struct Client {
     address: Url,
     token: Option<Secret>,
-    http: reqwest::blocking::Client,
+    /// Whether the token was created by avvoenv, rather than supplied, so
+    /// should be revoked once it's no longer needed.
+    created: bool,
+    auth: Option<Auth>,
+    namespace: Option<String>,
+    path_namespaces: Vec<(String, String)>,
+    mounts: RefCell<Vec<Mount>>,
+    http: http::Client,
 }
 
-pub struct Secret(String);
+/// The method used to obtain the token, kept so that the client can
+/// authenticate again once the token can no longer be renewed. `None` in the
+/// client means the token was supplied or unwrapped, so can't be obtained
+/// again.
+#[derive(Clone, Debug)]
+enum Auth {
+    Ldap {
+        username: String,
+        password: Secret,
+    },
+    AppId {
+        app_id: String,
+        user_id: Secret,
+    },
+    AppRole {
+        role_id: String,
+        secret_id: Option<Secret>,
+    },
+    Kubernetes {
+        mount: String,
+        role: String,
+        jwt: Secret,
+    },
+}
 
 #[derive(Debug)]
-pub enum ParseError {}
-
-impl std::error::Error for ParseError {}
-
-impl fmt::Display for ParseError {
-    fn fmt(&self, _: &mut fmt::Formatter) -> fmt::Result {
-        unreachable!()
-    }
+struct Mount {
+    namespace: Option<String>,
+    path: String,
+    kv_version: u8,
 }
 
-impl FromStr for Secret {
-    type Err = ParseError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Secret(s.to_owned()))
-    }
+#[derive(Deserialize)]
+struct Response<T> {
+    data: T,
 }
 
-impl fmt::Debug for Secret {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        f.debug_tuple("Secret")
-            .field(&"*".repeat(self.0.len()))
-            .finish()
-    }
+#[derive(Deserialize)]
+struct MountResponse {
+    path: String,
+    options: Option<HashMap<String, String>>,
 }
 
 #[derive(Deserialize)]
-struct Response<T> {
+struct Kv2Response<T> {
     data: T,
+    metadata: Option<Kv2Metadata>,
+}
+
+#[derive(Deserialize)]
+struct Kv2Metadata {
+    version: u64,
 }
 
 #[derive(Debug, Deserialize)]
@@ -64,9 +92,24 @@ struct AppIdAuthRequest<'a> {
     user_id: &'a str,
 }
 
+#[derive(Serialize)]
+struct AppRoleAuthRequest<'a> {
+    role_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    secret_id: Option<&'a str>,
+}
+
+#[derive(Serialize)]
+struct KubernetesAuthRequest<'a> {
+    role: &'a str,
+    jwt: &'a str,
+}
+
 #[derive(Deserialize)]
 struct AuthResponse {
     client_token: String,
+    #[serde(default)]
+    lease_duration: u64,
 }
 
 #[derive(Deserialize)]
@@ -74,9 +117,106 @@ struct AuthResponseWrapper {
     auth: AuthResponse,
 }
 
+/// A secret from a dynamic secrets engine, such as database credentials.
+#[derive(Deserialize)]
+pub struct Lease {
+    pub lease_id: String,
+    pub lease_duration: u64,
+    pub renewable: bool,
+    pub data: HashMap<String, serde_json::Value>,
+}
+
+/// A certificate and private key issued by a PKI secrets engine.
+#[derive(Deserialize)]
+pub struct Certificate {
+    pub certificate: String,
+    pub issuing_ca: String,
+    #[serde(default)]
+    pub ca_chain: Vec<String>,
+    pub private_key: String,
+    pub serial_number: String,
+    pub expiration: u64,
+}
+
+#[derive(Serialize)]
+struct Kv2WriteRequest<'a, T> {
+    data: &'a T,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    options: Option<Kv2WriteOptions>,
+}
+
+#[derive(Serialize)]
+struct Kv2WriteOptions {
+    cas: u64,
+}
+
+#[derive(Serialize)]
+struct IssueRequest<'a> {
+    common_name: &'a str,
+}
+
+#[derive(Serialize)]
+struct DecryptRequest<'a> {
+    batch_input: Vec<DecryptInput<'a>>,
+}
+
+#[derive(Serialize)]
+struct DecryptInput<'a> {
+    ciphertext: &'a str,
+}
+
+#[derive(Deserialize)]
+struct DecryptResponse {
+    batch_results: Vec<DecryptResult>,
+}
+
+#[derive(Deserialize)]
+struct DecryptResult {
+    plaintext: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct LeaseRequest<'a> {
+    lease_id: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    increment: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct LeaseRenewResponse {
+    lease_duration: u64,
+}
+
+/// Information about a response-wrapping token.
+#[derive(Debug, Deserialize)]
+pub struct WrapInfo {
+    pub creation_path: String,
+}
+
+#[derive(Serialize)]
+struct WrapLookupRequest<'a> {
+    token: &'a str,
+}
+
+#[derive(Debug, Deserialize)]
+struct TokenInfo {
+    ttl: u64,
+    #[serde(default)]
+    creation_ttl: u64,
+    #[serde(default)]
+    renewable: bool,
+}
+
 #[derive(Debug)]
 pub struct Error(ClientError);
 
+impl Error {
+    pub fn is_unavailable(&self) -> bool {
+        self.0.is_unavailable()
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         self.0.fmt(f)
@@ -96,10 +236,11 @@ impl<T: Into<ClientError>> From<T> for Error {
 }
 
 impl Client {
-    pub fn new(mut address: Url) -> Result<Client, Error> {
+    pub fn new(mut address: Url, tls: &http::Tls, retry: &http::Retry) -> Result<Client, Error> {
         if address.cannot_be_a_base() {
             return Err(ClientError::BaseUrlError(address).into());
         };
+        let http = http::client(&mut address, tls, retry)?;
         address
             .path_segments_mut()
             .expect("invalid base URL")
@@ -108,36 +249,429 @@ impl Client {
         Ok(Client {
             address,
             token: None,
-            http: reqwest::blocking::Client::new(),
+            created: false,
+            auth: None,
+            namespace: None,
+            path_namespaces: Vec::new(),
+            mounts: RefCell::new(Vec::new()),
+            http,
         })
     }
 
     pub fn token(&mut self, token: Secret) {
         self.token = Some(token);
+        self.created = false;
+    }
+
+    /// Set the Vault Enterprise namespace sent with every request.
+    pub fn namespace(&mut self, namespace: String) {
+        self.namespace = Some(namespace);
+    }
+
+    /// Use `namespace` rather than the default for requests for `path` and
+    /// paths below it. Where overrides overlap the longest path wins.
+    pub fn path_namespace(&mut self, path: &str, namespace: String) {
+        let path = path.trim_matches('/').to_owned();
+        self.path_namespaces.push((path, namespace));
+    }
+
+    pub fn ldap_auth(&mut self, username: &str, password: Secret) -> Result<(), Error> {
+        self.login(Auth::Ldap {
+            username: username.to_owned(),
+            password,
+        })
+    }
+
+    pub fn app_id_auth(&mut self, app_id: &str, user_id: &Secret) -> Result<(), Error> {
+        self.login(Auth::AppId {
+            app_id: app_id.to_owned(),
+            user_id: user_id.clone(),
+        })
     }
 
-    pub fn ldap_auth(&mut self, username: &str, password: &str) -> Result<(), Error> {
-        // workaround Vault (0.5.2?) being janky and (ldap?) auth only working
-        // against the leader
-        self.resolve_leader()?;
-        let request = LdapAuthRequest { password };
-        let response: AuthResponseWrapper =
-            self.post(&format!("auth/ldap/login/{}", username), &request)?;
+    pub fn approle_auth(&mut self, role_id: &str, secret_id: Option<Secret>) -> Result<(), Error> {
+        self.login(Auth::AppRole {
+            role_id: role_id.to_owned(),
+            secret_id,
+        })
+    }
+
+    pub fn kubernetes_auth(&mut self, mount: &str, role: &str, jwt: Secret) -> Result<(), Error> {
+        self.login(Auth::Kubernetes {
+            mount: mount.trim_matches('/').to_owned(),
+            role: role.to_owned(),
+            jwt,
+        })
+    }
+
+    /// Looks up a response-wrapping token, without unwrapping it.
+    pub fn wrap_info(&self, Secret(token): &Secret) -> Result<WrapInfo, Error> {
+        let request = WrapLookupRequest { token };
+        match self.post::<_, Response<WrapInfo>>("sys/wrapping/lookup", &request) {
+            Ok(response) => Ok(response.data),
+            Err(e @ Error(ClientError::ServerError(_))) => {
+                warn!("Wrapped token is invalid, it may have already been used");
+                Err(e)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Unwraps a response-wrapping token for a token, using the unwrapped token
+    /// from then on. As wrapping tokens can only be used once, the client can't
+    /// authenticate again once the unwrapped token expires, but as nothing
+    /// else has the unwrapped token it's revoked as if created by avvoenv.
+    pub fn unwrap_token(&mut self, wrapping_token: Secret) -> Result<(), Error> {
+        self.token(wrapping_token);
+        let response: AuthResponseWrapper = self.post("sys/wrapping/unwrap", &json!({}))?;
         self.token = Some(Secret(response.auth.client_token));
+        self.created = true;
+        self.auth = None;
         Ok(())
     }
 
-    pub fn app_id_auth(&mut self, app_id: &str, Secret(user_id): &Secret) -> Result<(), Error> {
-        let request = AppIdAuthRequest { user_id };
-        let response: AuthResponseWrapper =
-            self.post(&format!("auth/app-id/login/{}", app_id), &request)?;
+    /// Renews the token, returning its remaining TTL, or `None` if the token
+    /// never expires. If the token can't be renewed, or its renewal was capped
+    /// by its max TTL, the client authenticates again with the method it
+    /// originally used, where possible, revoking the token it replaces.
+    pub fn renew_token(&mut self) -> Result<Option<Duration>, Error> {
+        let info = match self.token_info() {
+            Ok(info) => info,
+            Err(e) if self.auth.is_some() => {
+                debug!("{:?}", e);
+                warn!("Unable to look up Vault token, authenticating again");
+                return self.reauthenticate();
+            }
+            Err(e) => return Err(e),
+        };
+        trace!("{:?}", info);
+        if info.ttl == 0 {
+            return Ok(None);
+        }
+        let mut ttl = info.ttl;
+        if info.renewable {
+            debug!("Renewing Vault token");
+            let response: AuthResponseWrapper = self.post("auth/token/renew-self", &json!({}))?;
+            ttl = response.auth.lease_duration;
+            if ttl >= info.creation_ttl {
+                return Ok(Some(Duration::from_secs(ttl)));
+            }
+            debug!("Vault token renewal was capped at {}s", ttl);
+        }
+        if self.auth.is_none() {
+            warn!("Vault token can not be renewed, and expires in {}s", ttl);
+            return Ok(Some(Duration::from_secs(ttl)));
+        }
+        self.reauthenticate()
+    }
+
+    /// Revokes the token, if it was created by avvoenv. Supplied tokens are
+    /// left alone, as they may be in use elsewhere.
+    pub fn revoke_token(&mut self) -> Result<(), Error> {
+        if !self.created {
+            debug!("Not revoking supplied Vault token");
+            return Ok(());
+        }
+        debug!("Revoking Vault token");
+        self.post_response("auth/token/revoke-self", &json!({}))?;
+        self.token = None;
+        Ok(())
+    }
+
+    /// Reads a secret from a dynamic secrets engine, creating a new lease.
+    pub fn read_lease(&self, path: &str) -> Result<Option<Lease>, Error> {
+        self.get_internal(path)
+    }
+
+    /// Renews a lease, requesting it be extended by `increment`, and returns
+    /// the new lease duration, which may be shorter if capped by a max TTL.
+    pub fn renew_lease(&self, lease_id: &str, increment: Duration) -> Result<Duration, Error> {
+        let request = LeaseRequest {
+            lease_id,
+            increment: Some(increment.as_secs()),
+        };
+        // lease IDs start with the path of the secret, so are in its namespace
+        let namespace = self.namespace_for(lease_id);
+        let response: LeaseRenewResponse = self.post_in(namespace, "sys/leases/renew", &request)?;
+        Ok(Duration::from_secs(response.lease_duration))
+    }
+
+    pub fn revoke_lease(&self, lease_id: &str) -> Result<(), Error> {
+        let request = LeaseRequest {
+            lease_id,
+            increment: None,
+        };
+        let namespace = self.namespace_for(lease_id);
+        self.post_response_in(namespace, "sys/leases/revoke", &request)?;
+        Ok(())
+    }
+
+    /// Issues a new certificate from the PKI secrets engine at `mount`.
+    pub fn issue_certificate(
+        &self,
+        mount: &str,
+        role: &str,
+        common_name: &str,
+    ) -> Result<Certificate, Error> {
+        let request = IssueRequest { common_name };
+        let response: Response<Certificate> =
+            self.post(&format!("{}/issue/{}", mount, role), &request)?;
+        Ok(response.data)
+    }
+
+    /// Decrypts each of `ciphertexts` with the Transit secrets engine at
+    /// `mount` in a single request, returning the base64 encoded plaintext,
+    /// or the error decrypting it, for each in order.
+    pub fn transit_decrypt(
+        &self,
+        mount: &str,
+        key: &str,
+        ciphertexts: &[&str],
+    ) -> Result<Vec<Result<String, String>>, Error> {
+        let request = DecryptRequest {
+            batch_input: ciphertexts
+                .iter()
+                .map(|ciphertext| DecryptInput { ciphertext })
+                .collect(),
+        };
+        let response: Response<DecryptResponse> =
+            self.post(&format!("{}/decrypt/{}", mount, key), &request)?;
+        Ok(response
+            .data
+            .batch_results
+            .into_iter()
+            .map(|result| match (result.plaintext, result.error) {
+                (_, Some(error)) => Err(error),
+                (Some(plaintext), None) => Ok(plaintext),
+                (None, None) => Ok(String::new()),
+            })
+            .collect())
+    }
+
+    /// Returns the remaining TTL of the token, or `None` if it never expires.
+    pub fn token_ttl(&self) -> Result<Option<Duration>, Error> {
+        match self.token_info()?.ttl {
+            0 => Ok(None),
+            ttl => Ok(Some(Duration::from_secs(ttl))),
+        }
+    }
+
+    fn token_info(&self) -> Result<TokenInfo, Error> {
+        let key = "auth/token/lookup-self";
+        match self.get_internal::<Response<TokenInfo>>(key)? {
+            Some(Response { data }) => Ok(data),
+            None => Err(ClientError::NotFound(self.address.join(key)?).into()),
+        }
+    }
+
+    fn reauthenticate(&mut self) -> Result<Option<Duration>, Error> {
+        let auth = match self.auth.clone() {
+            Some(auth) => auth,
+            None => return Ok(None),
+        };
+        info!("Authenticating with Vault again");
+        let replaced = self.token.take();
+        self.login(auth)?;
+        if let Some(replaced) = replaced {
+            self.revoke_replaced(replaced);
+        }
+        self.token_ttl()
+    }
+
+    /// Revokes a token that's been replaced by the one the client now uses.
+    fn revoke_replaced(&mut self, replaced: Secret) {
+        debug!("Revoking replaced Vault token");
+        let token = self.token.replace(replaced);
+        if let Err(e) = self.post_response("auth/token/revoke-self", &json!({})) {
+            debug!("{:?}", e);
+            warn!("Could not revoke the replaced Vault token: {}", e);
+        }
+        self.token = token;
+    }
+
+    fn login(&mut self, auth: Auth) -> Result<(), Error> {
+        // an expired token would otherwise be sent along with the login
+        self.token = None;
+        let response: AuthResponseWrapper = match auth {
+            Auth::Ldap {
+                ref username,
+                password: Secret(ref password),
+            } => {
+                // workaround Vault (0.5.2?) being janky and (ldap?) auth only
+                // working against the leader
+                self.resolve_leader()?;
+                let request = LdapAuthRequest { password };
+                self.post(&format!("auth/ldap/login/{}", username), &request)?
+            }
+            Auth::AppId {
+                ref app_id,
+                user_id: Secret(ref user_id),
+            } => {
+                let request = AppIdAuthRequest { user_id };
+                self.post(&format!("auth/app-id/login/{}", app_id), &request)?
+            }
+            Auth::AppRole {
+                ref role_id,
+                ref secret_id,
+            } => {
+                let request = AppRoleAuthRequest {
+                    role_id,
+                    secret_id: secret_id.as_ref().map(|Secret(s)| s.as_str()),
+                };
+                self.post("auth/approle/login", &request)?
+            }
+            Auth::Kubernetes {
+                ref mount,
+                ref role,
+                jwt: Secret(ref jwt),
+            } => {
+                let request = KubernetesAuthRequest { role, jwt };
+                self.post(&format!("auth/{}/login", mount), &request)?
+            }
+        };
+        trace!("Got Vault token with TTL {}s", response.auth.lease_duration);
         self.token = Some(Secret(response.auth.client_token));
+        self.created = true;
+        self.auth = Some(auth);
         Ok(())
     }
 
+    /// Returns the version of the KV secrets engine mounted at `key`, either 1
+    /// or 2. Defaults to 1 if the mount isn't found or the token can't look it
+    /// up, as with older versions of Vault.
+    pub fn kv_version(&self, key: &str) -> Result<u8, Error> {
+        Ok(self.mount(self.namespace_for(key), key)?.1)
+    }
+
+    /// Gets a secret, transparently handling KV version 1 and 2 mounts. For KV
+    /// version 2 mounts a specific `version` of the secret can be requested,
+    /// otherwise the latest version is returned.
+    pub fn get_version<T>(&self, key: &str, version: Option<u64>) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        Ok(self.get_versioned(key, version)?.map(|(data, _)| data))
+    }
+
+    /// Gets a secret as with `get_version`, along with the version returned
+    /// for KV version 2 mounts.
+    pub fn get_versioned<T>(
+        &self,
+        key: &str,
+        version: Option<u64>,
+    ) -> Result<Option<(T, Option<u64>)>, Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        let key = key.trim_start_matches('/');
+        let namespace = self.namespace_for(key);
+        let (mount, kv_version) = self.mount(namespace, key)?;
+        if kv_version != 2 {
+            if version.is_some() {
+                warn!("{:?} is not a KV version 2 mount, ignoring version", mount);
+            }
+            return Ok(self
+                .get_in::<Response<T>>(namespace, key)?
+                .map(|r| (r.data, None)));
+        }
+        let mut path = format!("{}data/{}", mount, &key[mount.len()..]);
+        if let Some(version) = version {
+            path = format!("{}?version={}", path, version);
+        }
+        Ok(self
+            .get_in::<Response<Kv2Response<T>>>(namespace, &path)?
+            .map(|r| (r.data.data, r.data.metadata.map(|m| m.version))))
+    }
+
+    /// Writes a secret, transparently handling KV version 1 and 2 mounts. For
+    /// KV version 2 mounts with `cas` the secret is only written if its
+    /// version is still `cas`, with 0 meaning it mustn't exist yet. Returns
+    /// whether the secret was written.
+    pub fn put_version<T>(&self, key: &str, data: &T, cas: Option<u64>) -> Result<bool, Error>
+    where
+        T: serde::ser::Serialize,
+    {
+        let key = key.trim_start_matches('/');
+        let namespace = self.namespace_for(key);
+        let (mount, kv_version) = self.mount(namespace, key)?;
+        if kv_version != 2 {
+            if cas.is_some() {
+                warn!("{:?} is not a KV version 2 mount, ignoring cas", mount);
+            }
+            self.post_response_in(namespace, key, data)?;
+            return Ok(true);
+        }
+        let path = format!("{}data/{}", mount, &key[mount.len()..]);
+        let request = Kv2WriteRequest {
+            data,
+            options: cas.map(|cas| Kv2WriteOptions { cas }),
+        };
+        match self.post_response_in(namespace, &path, &request) {
+            Ok(_) => Ok(true),
+            // Vault rejects a write with a stale cas as a bad request
+            Err(Error(ClientError::ServerError(ref response)))
+                if cas.is_some() && response.status() == reqwest::StatusCode::BAD_REQUEST =>
+            {
+                debug!("{:?}", response);
+                Ok(false)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn mount(&self, namespace: Option<&str>, key: &str) -> Result<(String, u8), Error> {
+        let key = key.trim_start_matches('/');
+        if let Some(mount) = self
+            .mounts
+            .borrow()
+            .iter()
+            .find(|m| m.namespace.as_deref() == namespace && key.starts_with(&m.path))
+        {
+            return Ok((mount.path.clone(), mount.kv_version));
+        }
+        trace!("Looking up Vault mount for {:?}", key);
+        let mount = match self.get_in::<Response<MountResponse>>(
+            namespace,
+            &format!("sys/internal/ui/mounts/{}", key),
+        ) {
+            Ok(Some(Response { data })) => Mount {
+                namespace: namespace.map(str::to_owned),
+                kv_version: data
+                    .options
+                    .as_ref()
+                    .and_then(|o| o.get("version"))
+                    .and_then(|v| v.parse().ok())
+                    .unwrap_or(1),
+                path: data.path,
+            },
+            // older versions of Vault don't have the endpoint, and tokens may
+            // not be allowed to read it, but as that's a guess it isn't kept,
+            // and the mount is looked up again next time
+            Ok(None) => return Ok(Client::assume_v1(key)),
+            Err(Error(ClientError::ServerError(ref response)))
+                if response.status() == reqwest::StatusCode::FORBIDDEN =>
+            {
+                return Ok(Client::assume_v1(key))
+            }
+            Err(e) => return Err(e),
+        };
+        debug!("Got Vault mount {:?}", mount);
+        let result = (mount.path.clone(), mount.kv_version);
+        self.mounts.borrow_mut().push(mount);
+        Ok(result)
+    }
+
+    fn assume_v1(key: &str) -> (String, u8) {
+        debug!(
+            "Unable to look up Vault mount for {:?}, assuming KV v1",
+            key
+        );
+        (format!("{}/", key.split('/').next().unwrap_or("")), 1)
+    }
+
     fn resolve_leader(&mut self) -> Result<(), Error> {
         trace!("Resolving Vault leader");
-        let info = match self.get_internal::<LeaderResponse>("/sys/leader")? {
+        let info = match self.get_in::<LeaderResponse>(None, "/sys/leader")? {
             Some(v) => v,
             None => {
                 warn!("Vault leader Not Found");
@@ -162,39 +696,102 @@ impl Client {
         Ok(())
     }
 
+    /// The namespace for requests for `key`, from the longest matching path
+    /// override, or the default namespace.
+    fn namespace_for(&self, key: &str) -> Option<&str> {
+        let key = key.trim_start_matches('/');
+        self.path_namespaces
+            .iter()
+            .filter(|(path, _)| {
+                path.is_empty()
+                    || key == path
+                    || (key.starts_with(path.as_str()) && key[path.len()..].starts_with('/'))
+            })
+            .max_by_key(|(path, _)| path.len())
+            .map(|(_, namespace)| namespace.as_str())
+            .or(self.namespace.as_deref())
+    }
+
     fn post<S, D>(&self, key: &str, data: &S) -> Result<D, Error>
     where
         S: serde::ser::Serialize,
         D: serde::de::DeserializeOwned,
+    {
+        self.post_in(self.namespace_for(key), key, data)
+    }
+
+    fn post_in<S, D>(&self, namespace: Option<&str>, key: &str, data: &S) -> Result<D, Error>
+    where
+        S: serde::ser::Serialize,
+        D: serde::de::DeserializeOwned,
+    {
+        let (url, response) = self.post_response_in(namespace, key, data)?;
+        Ok(response.json().map_err(|e| ClientError::with_url(url, e))?)
+    }
+
+    fn post_response<S>(
+        &self,
+        key: &str,
+        data: &S,
+    ) -> Result<(Url, reqwest::blocking::Response), Error>
+    where
+        S: serde::ser::Serialize,
+    {
+        self.post_response_in(self.namespace_for(key), key, data)
+    }
+
+    fn post_response_in<S>(
+        &self,
+        namespace: Option<&str>,
+        key: &str,
+        data: &S,
+    ) -> Result<(Url, reqwest::blocking::Response), Error>
+    where
+        S: serde::ser::Serialize,
     {
         let url = self.address.join(key.trim_start_matches(|c| c == '/'))?;
         let mut request = self.http.post(url.clone()).json(data);
+        if let Some(namespace) = namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
         trace!("{:?}", request);
         if let Some(Secret(ref token)) = self.token {
             request = request.header("X-Vault-Token", token.as_str());
         };
-        let response = request
-            .send()
+        let response = self
+            .http
+            .send(request)
             .map_err(|e| ClientError::with_url(url.clone(), e))?;
         trace!("{:?}", response);
         if !response.status().is_success() {
             return Err(ClientError::ServerError(response).into());
         }
-        Ok(response.json().map_err(|e| ClientError::with_url(url, e))?)
+        Ok((url, response))
     }
 
     fn get_internal<T>(&self, key: &str) -> Result<Option<T>, Error>
+    where
+        T: serde::de::DeserializeOwned + 'static,
+    {
+        self.get_in(self.namespace_for(key), key)
+    }
+
+    fn get_in<T>(&self, namespace: Option<&str>, key: &str) -> Result<Option<T>, Error>
     where
         T: serde::de::DeserializeOwned + 'static,
     {
         let url = self.address.join(key.trim_start_matches(|c| c == '/'))?;
         let mut request = self.http.get(url.clone());
+        if let Some(namespace) = namespace {
+            request = request.header("X-Vault-Namespace", namespace);
+        }
         trace!("{:?}", request);
         if let Some(Secret(ref token)) = self.token {
             request = request.header("X-Vault-Token", token.as_str());
         };
-        let response = request
-            .send()
+        let response = self
+            .http
+            .send(request)
             .map_err(|e| ClientError::with_url(url.clone(), e))?;
         trace!("{:?}", response);
         if response.status() == reqwest::StatusCode::NOT_FOUND {
@@ -216,6 +813,169 @@ impl crate::env::Client for Client {
     where
         T: serde::de::DeserializeOwned + 'static,
     {
-        Ok(self.get_internal::<Response<T>>(key)?.map(|r| r.data))
+        self.get_version(key, None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stub::{retry, Request, Response, Stub};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// A stub Vault, answering logins with a new token each time, and token
+    /// lookups with `lookup`.
+    fn vault<F>(lookup: F) -> (Stub, Client)
+    where
+        F: Fn(&Request) -> Response + Send + 'static,
+    {
+        let logins = AtomicUsize::new(0);
+        let stub = Stub::new(move |request| {
+            if request.path.ends_with("/login") {
+                let token = format!("token-{}", logins.fetch_add(1, Ordering::SeqCst));
+                (
+                    200,
+                    json!({"auth": {"client_token": token, "lease_duration": 60}}),
+                )
+            } else {
+                lookup(request)
+            }
+        });
+        let client = Client::new(stub.address.clone(), &http::Tls::default(), &retry()).unwrap();
+        (stub, client)
+    }
+
+    fn lookup(_: &Request) -> Response {
+        (200, json!({"data": {"ttl": 60, "creation_ttl": 60}}))
+    }
+
+    #[test]
+    fn approle_auth() {
+        let (stub, mut client) = vault(lookup);
+        client
+            .approle_auth("role", Some(Secret("secret".to_owned())))
+            .unwrap();
+        client.token_ttl().unwrap();
+        let requests = stub.requests();
+        assert_eq!(requests[0].path, "/v1/auth/approle/login");
+        assert_eq!(
+            requests[0].json(),
+            json!({"role_id": "role", "secret_id": "secret"})
+        );
+        assert_eq!(requests[1].header("x-vault-token"), Some("token-0"));
+    }
+
+    #[test]
+    fn approle_auth_without_secret_id() {
+        let (stub, mut client) = vault(lookup);
+        client.approle_auth("role", None).unwrap();
+        assert_eq!(stub.requests()[0].json(), json!({"role_id": "role"}));
+    }
+
+    #[test]
+    fn kubernetes_auth() {
+        let (stub, mut client) = vault(lookup);
+        client
+            .kubernetes_auth("/k8s/", "app", Secret("jwt".to_owned()))
+            .unwrap();
+        client.token_ttl().unwrap();
+        let requests = stub.requests();
+        assert_eq!(requests[0].path, "/v1/auth/k8s/login");
+        assert_eq!(requests[0].json(), json!({"role": "app", "jwt": "jwt"}));
+        assert_eq!(requests[1].header("x-vault-token"), Some("token-0"));
+    }
+
+    #[test]
+    fn authenticates_again() {
+        let (stub, mut client) = vault(|request| match request.header("x-vault-token") {
+            Some("token-0") => (403, json!({"errors": ["permission denied"]})),
+            _ => lookup(request),
+        });
+        client
+            .kubernetes_auth("kubernetes", "app", Secret("jwt".to_owned()))
+            .unwrap();
+        client.renew_token().unwrap();
+        let requests = stub.requests();
+        assert_eq!(requests[2].path, "/v1/auth/kubernetes/login");
+        assert_eq!(requests[3].path, "/v1/auth/token/revoke-self");
+        assert_eq!(requests[4].header("x-vault-token"), Some("token-1"));
+    }
+
+    #[test]
+    fn revokes_replaced_token() {
+        let (stub, mut client) = vault(|request| match request.path.as_str() {
+            "/v1/auth/token/renew-self" => (
+                200,
+                json!({"auth": {"client_token": "token-0", "lease_duration": 10}}),
+            ),
+            _ => (
+                200,
+                json!({"data": {"ttl": 10, "creation_ttl": 60, "renewable": true}}),
+            ),
+        });
+        client.approle_auth("role", None).unwrap();
+        client.renew_token().unwrap();
+        let revoked: Vec<_> = stub
+            .requests()
+            .into_iter()
+            .filter(|r| r.path == "/v1/auth/token/revoke-self")
+            .collect();
+        assert_eq!(revoked.len(), 1);
+        assert_eq!(revoked[0].header("x-vault-token"), Some("token-0"));
+        assert_eq!(
+            stub.requests().last().unwrap().header("x-vault-token"),
+            Some("token-1")
+        );
+    }
+
+    #[test]
+    fn revokes_unwrapped_token() {
+        let (stub, mut client) = vault(|request| match request.path.as_str() {
+            "/v1/sys/wrapping/unwrap" => (200, json!({"auth": {"client_token": "unwrapped"}})),
+            _ => (204, json!({})),
+        });
+        client.unwrap_token(Secret("wrapping".to_owned())).unwrap();
+        client.revoke_token().unwrap();
+        let request = stub.requests().pop().unwrap();
+        assert_eq!(request.path, "/v1/auth/token/revoke-self");
+        assert_eq!(request.header("x-vault-token"), Some("unwrapped"));
+    }
+
+    #[test]
+    fn leaves_supplied_token() {
+        let (stub, mut client) = vault(lookup);
+        client.token(Secret("supplied".to_owned()));
+        client.revoke_token().unwrap();
+        assert!(stub.requests().is_empty());
+    }
+
+    #[test]
+    fn finds_kv_version() {
+        let (stub, client) = vault(|_| {
+            let options = json!({"version": "2"});
+            (
+                200,
+                json!({"data": {"path": "secret/", "options": options}}),
+            )
+        });
+        assert_eq!(client.kv_version("secret/app").unwrap(), 2);
+        assert_eq!(client.kv_version("secret/global").unwrap(), 2);
+        assert_eq!(stub.requests().len(), 1);
+    }
+
+    #[test]
+    fn assumes_kv_v1_without_caching() {
+        for status in &[403, 404] {
+            let (stub, client) = vault(move |_| (*status, json!({"errors": []})));
+            assert_eq!(client.kv_version("secret/app").unwrap(), 1);
+            assert_eq!(client.kv_version("secret/app").unwrap(), 1);
+            assert_eq!(stub.requests().len(), 2);
+        }
+    }
+
+    #[test]
+    fn kv_version_errors() {
+        let (_stub, client) = vault(|_| (500, json!({"errors": []})));
+        assert!(client.kv_version("secret/app").is_err());
     }
 }